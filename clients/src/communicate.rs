@@ -1,4 +1,8 @@
-use interface::{InitializationPacket, NetworkPacket, Sendable};
+use interface::{
+    Command, CommandAck, CommandPacket, CommandStatus, EventKind, EventPacket,
+    InitializationPacket, LogRecord, Message, NetworkPacket, RegistrationResponse, Sendable,
+    BUFFER_SIZE,
+};
 use std::{
     io::{prelude::*, BufReader},
     net::{SocketAddr, TcpStream, UdpSocket},
@@ -6,24 +10,51 @@ use std::{
 };
 
 const LOOP_DELAY_TIME: u64 = 2;
+/// How long to wait for an ack before retransmitting.
+const ACK_TIMEOUT_MS: u64 = 500;
+/// Total number of transmissions of an acknowledged packet before giving up.
+const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Timestamps before 2020-01-01 mean the clock has not been set yet.
+const MIN_VALID_TIMESTAMP_MS: u64 = 1_577_836_800_000;
 
 struct Connection {
-    config: Vec<u8>,
+    config: InitializationPacket,
     udp_endpoint: SocketAddr,
     tcp_endpoint: SocketAddr,
     sock: UdpSocket,
+    sequence: u32,
+    acknowledge_readings: bool,
+    device_id: u32,
+    sample_interval: time::Duration,
+    /// Server time minus local time at registration, in milliseconds.
+    clock_offset_ms: i64,
+    /// Commands up to this id were already executed and only need acking again. Starts
+    /// over on reboot, the server stops resending commands once it sees the boot event.
+    last_command_id: u32,
+    /// Left by commands that arrived while waiting for an ack, done on the next poll.
+    follow_up: FollowUp,
+}
+
+/// Work a command leaves for after the poll window.
+enum FollowUp {
+    None,
+    Heartbeat,
+    ReRegister,
+    Reboot,
 }
 
 impl Connection {
     pub fn from(
-        config: Vec<u8>,
+        config: InitializationPacket,
         tcp_endpoint: SocketAddr,
         udp_endpoint: SocketAddr,
+        acknowledge_readings: bool,
     ) -> UnconfiguredConnection {
         UnconfiguredConnection {
             config,
             tcp_endpoint,
             udp_endpoint,
+            acknowledge_readings,
         }
     }
 }
@@ -31,32 +62,90 @@ impl Connection {
 trait ClientCommunication {
     fn send(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>>;
     fn check_connection(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>>;
+    fn send_event(
+        self: Box<Self>,
+        event: EventPacket,
+    ) -> std::io::Result<Box<dyn ClientCommunication>>;
+    /// How long to wait between readings.
+    fn sample_interval(&self) -> time::Duration;
+    /// Waits for `wait`, acting on any commands the server sends meanwhile.
+    fn poll(self: Box<Self>, wait: time::Duration)
+        -> std::io::Result<Box<dyn ClientCommunication>>;
+}
+
+fn local_millis() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Milliseconds since the unix epoch corrected by the server clock, or 0 if unknown.
+fn device_timestamp(clock_offset_ms: i64) -> u64 {
+    to_server_time(local_millis(), clock_offset_ms)
+}
+
+fn to_server_time(local_ms: u64, clock_offset_ms: i64) -> u64 {
+    let corrected = local_ms.saturating_add_signed(clock_offset_ms);
+    if corrected < MIN_VALID_TIMESTAMP_MS {
+        0
+    } else {
+        corrected
+    }
+}
+
+/// Sends the initialization packet and waits for the server's verdict.
+fn register(
+    config: &InitializationPacket,
+    tcp_endpoint: SocketAddr,
+) -> std::io::Result<RegistrationResponse> {
+    let config = config
+        .clone()
+        .to_bytes()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let mut stream = TcpStream::connect(tcp_endpoint)?;
+    stream.write_all(&config)?;
+    let mut buf = String::new();
+    let mut buf_reader = BufReader::new(stream);
+
+    buf_reader.read_to_string(&mut buf)?;
+    let response = RegistrationResponse::from_bytes(buf.as_bytes())
+        .map_err(|e| std::io::Error::other(format!("Invalid registration response: {}", e)))?;
+
+    if !response.is_ok() {
+        log::error!(
+            "Registration rejected by {} with {}: {}",
+            tcp_endpoint,
+            response.status,
+            response.reason
+        );
+        return Err(std::io::Error::other(format!(
+            "Server returned {}: {}",
+            response.status, response.reason
+        )));
+    }
+    Ok(response)
 }
 
 struct UnconfiguredConnection {
-    config: Vec<u8>,
+    config: InitializationPacket,
     tcp_endpoint: SocketAddr,
     udp_endpoint: SocketAddr,
+    acknowledge_readings: bool,
 }
 
 impl ClientCommunication for UnconfiguredConnection {
     fn send(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
-        {
-            log::info!("Initializing connection with {}", self.tcp_endpoint);
-            let mut stream = TcpStream::connect(self.tcp_endpoint)?;
-            stream.write_all(&self.config)?;
-            let mut buf = String::new();
-            let mut buf_reader = BufReader::new(stream);
-
-            buf_reader.read_to_string(&mut buf)?;
-            assert_eq!(buf, "200");
-
-            log::info!(
-                "Connection with {} Successful! binding to Udp Socket: {}",
-                self.tcp_endpoint,
-                self.udp_endpoint
-            );
-        };
+        log::info!("Initializing connection with {}", self.tcp_endpoint);
+        let response = register(&self.config, self.tcp_endpoint)?;
+
+        log::info!(
+            "Connection with {} Successful! Registered as device {:?} speaking {}, binding to Udp Socket: {}",
+            self.tcp_endpoint,
+            response.device_id,
+            response.protocol_version,
+            self.udp_endpoint
+        );
 
         let sock = UdpSocket::bind(self.udp_endpoint)?;
 
@@ -67,47 +156,291 @@ impl ClientCommunication for UnconfiguredConnection {
             sock,
             udp_endpoint: self.udp_endpoint,
             tcp_endpoint: self.tcp_endpoint,
+            sequence: 0,
+            acknowledge_readings: self.acknowledge_readings,
+            device_id: response.device_id.unwrap_or_default(),
+            sample_interval: time::Duration::from_millis(response.sample_interval.into()),
+            clock_offset_ms: response.server_time as i64 - local_millis() as i64,
+            last_command_id: 0,
+            follow_up: FollowUp::None,
         }))
     }
 
     fn check_connection(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
         self.send()
     }
+
+    fn send_event(
+        self: Box<Self>,
+        event: EventPacket,
+    ) -> std::io::Result<Box<dyn ClientCommunication>> {
+        // events need a registered device, configure first
+        self.check_connection()?.send_event(event)
+    }
+
+    fn sample_interval(&self) -> time::Duration {
+        time::Duration::from_secs(LOOP_DELAY_TIME)
+    }
+
+    fn poll(
+        self: Box<Self>,
+        wait: time::Duration,
+    ) -> std::io::Result<Box<dyn ClientCommunication>> {
+        thread::sleep(wait);
+        Ok(self)
+    }
+}
+
+impl Connection {
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    /// Sends `bytes` until the server acknowledges `sequence` or the retry budget runs out.
+    ///
+    /// Returns whether an ack was recieved.
+    fn send_acknowledged(&mut self, bytes: &[u8], sequence: u32) -> std::io::Result<bool> {
+        for attempt in 1..=MAX_SEND_ATTEMPTS {
+            self.sock.send_to(bytes, self.udp_endpoint)?;
+            if self.wait_for_ack(sequence)? {
+                log::info!(
+                    "Packet {} acknowledged after {} attempts",
+                    sequence,
+                    attempt
+                );
+                return Ok(true);
+            }
+            log::warn!("No ack for packet {} (attempt {})", sequence, attempt);
+        }
+
+        log::error!(
+            "Giving up on packet {} after {} attempts",
+            sequence,
+            MAX_SEND_ATTEMPTS
+        );
+        Ok(false)
+    }
+
+    /// Waits up to [`ACK_TIMEOUT_MS`] for the server to acknowledge `sequence`. Commands
+    /// arriving meanwhile are executed, what they leave to do waits for the next poll.
+    fn wait_for_ack(&mut self, sequence: u32) -> std::io::Result<bool> {
+        let timeout = time::Duration::from_millis(ACK_TIMEOUT_MS);
+        self.sock.set_read_timeout(Some(timeout))?;
+        let mut buf = [0u8; BUFFER_SIZE];
+        let deadline = time::Instant::now() + timeout;
+
+        while time::Instant::now() < deadline {
+            let len = match self.sock.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            match Message::from_bytes(&buf[..len]) {
+                Ok(Message::Ack(ack)) if ack.sequence == sequence => return Ok(true),
+                Ok(Message::Ack(ack)) => log::debug!("Late ack for packet {}", ack.sequence),
+                Ok(Message::Command(packet)) => {
+                    let follow_up = self.handle_command(packet)?;
+                    self.defer(follow_up);
+                }
+                Ok(other) => log::warn!("Ignoring unexpected message {:?}", other),
+                Err(e) => log::warn!("Unable to decode reply: {}", e),
+            }
+        }
+        Ok(false)
+    }
+
+    fn execute(&mut self, command: Command) -> (CommandStatus, FollowUp) {
+        log::info!("Executing command {:?}", command);
+        match command {
+            Command::SetSampleInterval(interval) => {
+                self.sample_interval = time::Duration::from_millis(interval.into());
+                (CommandStatus::Completed, FollowUp::None)
+            }
+            Command::SetLocation(location) => {
+                self.config.location = location;
+                (CommandStatus::Completed, FollowUp::ReRegister)
+            }
+            Command::Identify => {
+                log::warn!("Identify requested but this board has no indicator configured");
+                (CommandStatus::Unsupported, FollowUp::None)
+            }
+            Command::Reboot => (CommandStatus::Completed, FollowUp::Reboot),
+            Command::RequestHeartbeat => (CommandStatus::Completed, FollowUp::Heartbeat),
+            Command::RequestReRegistration => (CommandStatus::Completed, FollowUp::ReRegister),
+        }
+    }
+
+    /// Sends log records buffered by [`crate::logger`]. A record stays buffered until the
+    /// server acknowledges it, sending alone succeeds whether the server is up or not.
+    ///
+    /// Each record gets one attempt and nothing is logged on failure, the warnings would
+    /// only push the records they are about out of the buffer.
+    fn forward_logs(&mut self) {
+        let mut records = crate::logger::drain().into_iter();
+        while let Some(record) = records.next() {
+            let sequence = self.next_sequence();
+            // the buffer keeps local time, only what is sent is converted
+            let acked = LogRecord {
+                sequence,
+                timestamp: to_server_time(record.timestamp, self.clock_offset_ms),
+                ..record.clone()
+            }
+            .to_bytes()
+            .map_err(|e| std::io::Error::other(e.to_string()))
+            .and_then(|bytes| self.sock.send_to(&bytes, self.udp_endpoint))
+            .and_then(|_| self.wait_for_ack(sequence));
+            if !matches!(acked, Ok(true)) {
+                // unreachable, try again after the next reading
+                crate::logger::requeue(std::iter::once(record).chain(records).collect());
+                return;
+            }
+        }
+    }
+
+    /// Keeps `follow_up` for the end of the next poll, replacing an earlier one.
+    fn defer(&mut self, follow_up: FollowUp) {
+        if !matches!(follow_up, FollowUp::None) {
+            self.follow_up = follow_up;
+        }
+    }
+
+    fn handle_command(&mut self, packet: CommandPacket) -> std::io::Result<FollowUp> {
+        let (status, follow_up) = if packet.id <= self.last_command_id {
+            // our ack got lost, the server is resending something we already did
+            log::info!("Command {} already executed", packet.id);
+            (CommandStatus::Completed, FollowUp::None)
+        } else {
+            self.last_command_id = packet.id;
+            self.execute(packet.command)
+        };
+
+        let bytes = CommandAck::new(packet.id, status)
+            .to_bytes()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.sock.send_to(&bytes, self.udp_endpoint)?;
+        Ok(follow_up)
+    }
 }
 
 impl ClientCommunication for Connection {
-    fn send(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
+    fn send(mut self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
+        let sequence = self.next_sequence();
         let data = NetworkPacket {
+            sequence,
+            ack_requested: self.acknowledge_readings,
+            timestamp: device_timestamp(self.clock_offset_ms),
             data: vec![32., 43.],
             ..Default::default()
         };
         log::info!("Attempting to send data to {}", self.udp_endpoint);
+        let bytes = data.to_bytes().unwrap();
 
-        match self
-            .sock
-            .send_to(&data.to_bytes().unwrap(), self.udp_endpoint)
-        {
-            Ok(len) => log::info!("{:?} bytes sent to {}", len, self.udp_endpoint),
-            Err(e) => log::error!("Error in sending message to address: {}", e),
-        };
+        if self.acknowledge_readings {
+            if let Err(e) = self.send_acknowledged(&bytes, sequence) {
+                log::error!("Error in sending message to address: {}", e);
+            }
+        } else {
+            match self.sock.send_to(&bytes, self.udp_endpoint) {
+                Ok(len) => log::info!("{:?} bytes sent to {}", len, self.udp_endpoint),
+                Err(e) => log::error!("Error in sending message to address: {}", e),
+            };
+        }
+
+        self.forward_logs();
         Ok(self)
     }
 
-    fn check_connection(self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
+    fn check_connection(mut self: Box<Self>) -> std::io::Result<Box<dyn ClientCommunication>> {
         log::info!("Checking connection with {}", self.tcp_endpoint);
-        let mut stream = TcpStream::connect(&self.tcp_endpoint)?;
-        stream.write_all(&self.config)?;
-        let mut buf = String::new();
-        let mut buf_reader = BufReader::new(stream);
-
-        buf_reader.read_to_string(&mut buf)?;
-        match buf.as_str() {
-            "200" => return Ok(self),
-            _ => {
-                return Err(std::io::Error::other(format!(
-                    "Server returned non-200: {}",
-                    buf
-                )))
+        let response = register(&self.config, self.tcp_endpoint)?;
+
+        if let Some(device_id) = response.device_id {
+            if device_id != self.device_id {
+                log::warn!("Device id changed from {} to {}", self.device_id, device_id);
+                self.device_id = device_id;
+            }
+        }
+        self.sample_interval = time::Duration::from_millis(response.sample_interval.into());
+        self.clock_offset_ms = response.server_time as i64 - local_millis() as i64;
+        Ok(self)
+    }
+
+    fn send_event(
+        mut self: Box<Self>,
+        event: EventPacket,
+    ) -> std::io::Result<Box<dyn ClientCommunication>> {
+        let sequence = self.next_sequence();
+        let event = EventPacket {
+            sequence,
+            timestamp: device_timestamp(self.clock_offset_ms),
+            ..event
+        };
+        log::info!(
+            "Sending {:?} event on channel {} to {}",
+            event.kind,
+            event.channel,
+            self.udp_endpoint
+        );
+
+        let bytes = event
+            .to_bytes()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.send_acknowledged(&bytes, sequence)?;
+        Ok(self)
+    }
+
+    fn sample_interval(&self) -> time::Duration {
+        self.sample_interval
+    }
+
+    fn poll(
+        mut self: Box<Self>,
+        wait: time::Duration,
+    ) -> std::io::Result<Box<dyn ClientCommunication>> {
+        let deadline = time::Instant::now() + wait;
+        let mut buf = [0u8; BUFFER_SIZE];
+
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.sock.set_read_timeout(Some(remaining))?;
+
+            let len = match self.sock.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            match Message::from_bytes(&buf[..len]) {
+                Ok(Message::Command(packet)) => {
+                    let follow_up = self.handle_command(packet)?;
+                    self.defer(follow_up);
+                }
+                Ok(Message::Ack(ack)) => log::debug!("Late ack for packet {}", ack.sequence),
+                Ok(other) => log::warn!("Ignoring unexpected message {:?}", other),
+                Err(e) => log::warn!("Unable to decode message: {}", e),
+            }
+        }
+
+        match std::mem::replace(&mut self.follow_up, FollowUp::None) {
+            FollowUp::None => Ok(self),
+            FollowUp::Heartbeat => self.send(),
+            FollowUp::ReRegister => self.check_connection(),
+            FollowUp::Reboot => {
+                log::warn!("Rebooting on server request");
+                esp_idf_svc::hal::reset::restart();
             }
         }
     }
@@ -121,6 +454,8 @@ pub fn run_server() -> Result<(), std::io::Error> {
     let tcp_destination = format!("{}:{}", remote_server, tcp_port);
     let udp_destination = format!("{}:{}", remote_server, udp_port);
     let local_addr = "0.0.0.0:8004";
+    // readings are only retransmitted when built with ACK_READINGS set
+    let acknowledge_readings = option_env!("ACK_READINGS").is_some();
 
     let init_packet = InitializationPacket {
         version: "0.0".to_string(),
@@ -137,16 +472,23 @@ pub fn run_server() -> Result<(), std::io::Error> {
     log::info!("Connecting to Remote Address: {}", udp_addr);
 
     let mut connection: Box<dyn ClientCommunication> = Box::new(Connection::from(
-        init_packet.to_bytes().unwrap(),
+        init_packet,
         tcp_addr,
         udp_addr,
+        acknowledge_readings,
     ));
 
+    connection = connection.send_event(EventPacket {
+        kind: EventKind::Boot,
+        ..Default::default()
+    })?;
+
     let mut counter = 0;
     loop {
         connection = connection.send()?;
         counter += 1;
-        thread::sleep(time::Duration::from_secs(LOOP_DELAY_TIME));
+        let wait = connection.sample_interval();
+        connection = connection.poll(wait)?;
         if counter > 12 {
             connection = connection.check_connection()?;
             counter = 0;
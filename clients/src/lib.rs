@@ -1,2 +1,3 @@
 pub mod wifi;
 pub mod communicate;
+pub mod logger;
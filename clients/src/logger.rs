@@ -0,0 +1,135 @@
+use esp_idf_svc::log::EspLogger;
+use interface::{LogLevel, LogRecord};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Records kept while the server is unreachable, the oldest are dropped first.
+const BUFFER_CAPACITY: usize = 32;
+/// At most this many records are forwarded per `RATE_WINDOW`.
+const RATE_LIMIT: u32 = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+static LOGGER: ForwardingLogger = ForwardingLogger::new();
+
+/// Prints everything through [`EspLogger`] and keeps warnings and errors for the server.
+pub struct ForwardingLogger {
+    inner: EspLogger,
+    state: Mutex<ForwardState>,
+}
+
+struct ForwardState {
+    buffer: VecDeque<LogRecord>,
+    window_start: Option<Instant>,
+    in_window: u32,
+    dropped: u32,
+}
+
+impl ForwardingLogger {
+    const fn new() -> Self {
+        Self {
+            inner: EspLogger::new(),
+            state: Mutex::new(ForwardState {
+                buffer: VecDeque::new(),
+                window_start: None,
+                in_window: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    fn forward(&self, level: LogLevel, target: &str, message: &str) {
+        // never log from in here, it would recurse into this logger
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        let window_expired = state
+            .window_start
+            .map_or(true, |start| now.duration_since(start) >= RATE_WINDOW);
+        if window_expired {
+            state.window_start = Some(now);
+            state.in_window = 0;
+            if state.dropped > 0 {
+                let note = format!("{} log records dropped by rate limit", state.dropped);
+                state.dropped = 0;
+                push(
+                    &mut state.buffer,
+                    LogRecord::new(LogLevel::Warn, timestamp, "logger", &note),
+                );
+            }
+        }
+
+        if state.in_window >= RATE_LIMIT {
+            state.dropped += 1;
+            return;
+        }
+        state.in_window += 1;
+        push(
+            &mut state.buffer,
+            LogRecord::new(level, timestamp, target, message),
+        );
+    }
+}
+
+fn push(buffer: &mut VecDeque<LogRecord>, record: LogRecord) {
+    if buffer.len() >= BUFFER_CAPACITY {
+        buffer.pop_front();
+    }
+    buffer.push_back(record);
+}
+
+impl log::Log for ForwardingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.inner.log(record);
+
+        let level = match record.level() {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            _ => return,
+        };
+        self.forward(level, record.target(), &record.args().to_string());
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the forwarding logger in place of `EspLogger::initialize_default`.
+pub fn initialize() {
+    log::set_logger(&LOGGER)
+        .map(|()| LOGGER.inner.initialize())
+        .unwrap();
+}
+
+/// Takes every buffered record, oldest first. Timestamps are in local device time.
+pub fn drain() -> Vec<LogRecord> {
+    match LOGGER.state.lock() {
+        Ok(mut state) => state.buffer.drain(..).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Puts records that could not be sent back in front of the buffer.
+pub fn requeue(records: Vec<LogRecord>) {
+    if let Ok(mut state) = LOGGER.state.lock() {
+        for record in records.into_iter().rev() {
+            if state.buffer.len() >= BUFFER_CAPACITY {
+                break;
+            }
+            state.buffer.push_front(record);
+        }
+    }
+}
@@ -5,7 +5,7 @@ load_dotenv::load_dotenv!("../.env");
 fn main() {
     esp_idf_svc::sys::link_patches();
 
-    esp_idf_svc::log::EspLogger::initialize_default();
+    clients::logger::initialize();
 
     let _wifi = clients::wifi::setup_wifi();
 
@@ -9,6 +9,12 @@ pub enum ConverterError {
 #[derive(Debug, PartialEq)]
 pub struct NetworkPacket {
     pub version: String,
+    pub sequence: u32,
+    /// Ask the server for an [`AckPacket`] once the readings are stored.
+    pub ack_requested: bool,
+    /// Milliseconds since the unix epoch when the readings were taken, in server time.
+    /// 0 if the device has no clock, the server then uses the time it received them.
+    pub timestamp: u64,
     pub data: Vec<f32>,
 }
 
@@ -20,15 +26,109 @@ pub trait Sendable {
 
 pub const BUFFER_SIZE: usize = 1024;
 
+/// Binary packets reserve the first `HEADER_SIZE` bytes for metadata.
+///
+/// | bytes  | field                                  |
+/// |--------|----------------------------------------|
+/// | 0..2   | version as two ascii digits            |
+/// | 2      | packet kind, see [`PacketKind`]        |
+/// | 3      | flags, see [`FLAG_ACK_REQUESTED`]      |
+/// | 4..8   | sequence number (u32 le)               |
+/// | 8..    | kind specific fields                   |
+pub const HEADER_SIZE: usize = 64;
+
+/// Header flag asking the server to acknowledge the packet.
+pub const FLAG_ACK_REQUESTED: u8 = 0b0000_0001;
+
+/// Discriminates the binary packets sent over udp.
+///
+/// `Data` is zero so packets written before the kind byte existed still decode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketKind {
+    Data = 0,
+    Event = 1,
+    Ack = 2,
+    Command = 3,
+    CommandAck = 4,
+    Log = 5,
+}
+
+impl TryFrom<u8> for PacketKind {
+    type Error = ConverterError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Event),
+            2 => Ok(Self::Ack),
+            3 => Ok(Self::Command),
+            4 => Ok(Self::CommandAck),
+            5 => Ok(Self::Log),
+            _ => Err(ConverterError::BytesConvertError(format!(
+                "unknown packet kind: {}",
+                value
+            ))),
+        }
+    }
+}
+
 impl Default for NetworkPacket {
     fn default() -> Self {
         Self {
             version: "0.0".to_string(),
+            sequence: 0,
+            ack_requested: false,
+            timestamp: 0,
             data: vec![],
         }
     }
 }
 
+fn write_header(
+    bytes: &mut [u8],
+    version: &str,
+    kind: PacketKind,
+    sequence: u32,
+) -> Result<(), ConverterError> {
+    if version != "0.0" {
+        return Err(ConverterError::BytesConvertError(
+            "version not existing".to_string(),
+        ));
+    }
+    let mut version_iter = version.bytes();
+    // encode version in first two bytes
+    bytes[0] = version_iter.next().unwrap();
+    version_iter.next();
+    bytes[1] = version_iter.next().unwrap();
+    bytes[2] = kind as u8;
+    bytes[4..8].copy_from_slice(&sequence.to_le_bytes());
+    Ok(())
+}
+
+/// Checks the header of `bytes` and returns the version, kind and sequence number.
+fn read_header(bytes: &[u8]) -> Result<(String, PacketKind, u32), ConverterError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(ConverterError::BytesConvertError(format!(
+            "packet shorter than header: {} bytes",
+            bytes.len()
+        )));
+    }
+    let major = bytes[0] as char;
+    let minor = bytes[1] as char;
+    match (major, minor) {
+        ('0', '0') => {}
+        _ => {
+            return Err(ConverterError::BytesConvertError(format!(
+                "version not existing: {}.{}",
+                major, minor
+            )));
+        }
+    }
+    let kind = PacketKind::try_from(bytes[2])?;
+    let sequence = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    Ok((format!("{}.{}", major, minor), kind, sequence))
+}
+
 fn write_into_buffer<'a>(
     bytes: &'a mut Vec<u8>,
     source_slice: &'a [u8],
@@ -56,40 +156,34 @@ impl Sendable for NetworkPacket {
     type Item = Self;
 
     fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
-        match self.version.as_str() {
-            "0.0" => {
-                let mut bytes = vec![0u8; BUFFER_SIZE];
-                let mut version_iter = self.version.bytes();
-                // encode version in first two bytes
-                bytes[0] = version_iter.next().unwrap();
-                version_iter.next();
-                bytes[1] = version_iter.next().unwrap();
+        let mut bytes = vec![0u8; BUFFER_SIZE];
+        write_header(&mut bytes, &self.version, PacketKind::Data, self.sequence)?;
+        if self.ack_requested {
+            bytes[3] |= FLAG_ACK_REQUESTED;
+        }
+        bytes[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
 
-                write_into_buffer(&mut bytes, f32_vec_to_u8_vec(&self.data), 64, None);
+        write_into_buffer(&mut bytes, f32_vec_to_u8_vec(&self.data), 64, None);
 
-                return Ok(bytes);
-            }
-            _ => {
-                return Err(ConverterError::BytesConvertError(
-                    "version not existing".to_string(),
-                ));
-            }
-        };
+        return Ok(bytes);
     }
     fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
-        let major = bytes[0] as char;
-        let minor = bytes[1] as char;
-
-        match (major, minor) {
-            ('0', '0') => {
-                let version = format!("{}.{}", major, minor);
+        let (version, kind, sequence) = read_header(bytes)?;
+        match kind {
+            PacketKind::Data => {
                 let data = u8_to_f32_vec(&bytes[64..]);
-                return Ok(Self::Item { version, data });
+                return Ok(Self::Item {
+                    version,
+                    sequence,
+                    ack_requested: bytes[3] & FLAG_ACK_REQUESTED != 0,
+                    timestamp: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                    data,
+                });
             }
             _ => {
                 return Err(ConverterError::BytesConvertError(format!(
-                    "version not existing: {}.{}",
-                    major, minor
+                    "expected data packet, got {:?}",
+                    kind
                 )));
             }
         };
@@ -146,30 +240,24 @@ impl Sendable for InitializationPacket {
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
-        let parts = std::str::from_utf8(bytes).unwrap().to_string();
+        let parts = text_from_bytes(bytes)?;
         let mut parts = parts.split(';');
 
-        let version = parts.next().unwrap().to_string();
+        let version = next_field(&mut parts, "version")?.to_string();
         match version.as_str() {
             "0.0" => {
-                let location = parts.next().unwrap().to_string();
-                let data_map = parts
-                    .next()
-                    .unwrap()
+                let location = next_field(&mut parts, "location")?.to_string();
+                let data_map = next_field(&mut parts, "data map")?
                     .split(',')
                     .map(|v| v.to_string())
                     .collect();
 
-                let units = parts
-                    .next()
-                    .unwrap()
+                let units = next_field(&mut parts, "units")?
                     .split(',')
                     .map(|v| v.to_string())
                     .collect();
 
-                let measureands = parts
-                    .next()
-                    .unwrap()
+                let measureands = next_field(&mut parts, "measureands")?
                     .split(',')
                     .map(|v| v.trim_end().to_string())
                     .collect();
@@ -190,6 +278,764 @@ impl Sendable for InitializationPacket {
     }
 }
 
+fn text_from_bytes(bytes: &[u8]) -> Result<String, ConverterError> {
+    std::str::from_utf8(bytes)
+        .map(|v| v.to_string())
+        .map_err(|e| ConverterError::BytesConvertError(format!("invalid utf-8: {}", e)))
+}
+
+fn next_field<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    name: &str,
+) -> Result<&'a str, ConverterError> {
+    parts
+        .next()
+        .ok_or_else(|| ConverterError::BytesConvertError(format!("missing field: {}", name)))
+}
+
+/// Registration was accepted.
+pub const STATUS_OK: u16 = 200;
+/// The initialization packet could not be parsed or is inconsistent.
+pub const STATUS_BAD_REQUEST: u16 = 400;
+/// The device is not allowed to register, e.g. because of its location.
+pub const STATUS_FORBIDDEN: u16 = 403;
+/// The server failed to store the registration.
+pub const STATUS_SERVER_ERROR: u16 = 500;
+/// The server does not speak the protocol version of the device.
+pub const STATUS_UNSUPPORTED_VERSION: u16 = 505;
+
+/// The server's answer to an [`InitializationPacket`], sent back over tcp.
+///
+/// Encoded as a single `;` separated line like the initialization packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistrationResponse {
+    pub version: String,
+    pub status: u16,
+    /// Why the registration was rejected, empty on success.
+    pub reason: String,
+    pub device_id: Option<u32>,
+    /// Milliseconds since the unix epoch on the server.
+    pub server_time: u64,
+    /// The protocol version the device should use from now on.
+    pub protocol_version: String,
+    /// How often the device should send readings, in milliseconds.
+    pub sample_interval: u32,
+}
+
+impl RegistrationResponse {
+    pub fn accepted(
+        device_id: u32,
+        server_time: u64,
+        protocol_version: String,
+        sample_interval: u32,
+    ) -> Self {
+        Self {
+            version: "0.0".to_string(),
+            status: STATUS_OK,
+            reason: String::new(),
+            device_id: Some(device_id),
+            server_time,
+            protocol_version,
+            sample_interval,
+        }
+    }
+
+    pub fn rejected(status: u16, reason: String, server_time: u64) -> Self {
+        Self {
+            version: "0.0".to_string(),
+            status,
+            reason,
+            device_id: None,
+            server_time,
+            protocol_version: String::new(),
+            sample_interval: 0,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == STATUS_OK
+    }
+}
+
+impl Sendable for RegistrationResponse {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        // the reason is free text, keep it from breaking the framing
+        let reason = self.reason.replace([';', '\n'], " ");
+        let device_id = self.device_id.map(|v| v.to_string()).unwrap_or_default();
+
+        let sendable_str = format!(
+            "{};{};{};{};{};{};{}\n",
+            self.version,
+            self.status,
+            reason,
+            device_id,
+            self.server_time,
+            self.protocol_version,
+            self.sample_interval
+        );
+        Ok(sendable_str.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let parts = text_from_bytes(bytes)?;
+        let mut parts = parts.trim_end().split(';');
+
+        let version = next_field(&mut parts, "version")?.to_string();
+        match version.as_str() {
+            "0.0" => {
+                let status = parse_field(next_field(&mut parts, "status")?, "status")?;
+                let reason = next_field(&mut parts, "reason")?.to_string();
+                let device_id = match next_field(&mut parts, "device id")? {
+                    "" => None,
+                    v => Some(parse_field(v, "device id")?),
+                };
+                let server_time =
+                    parse_field(next_field(&mut parts, "server time")?, "server time")?;
+                let protocol_version = next_field(&mut parts, "protocol version")?.to_string();
+                let sample_interval = parse_field(
+                    next_field(&mut parts, "sample interval")?,
+                    "sample interval",
+                )?;
+
+                Ok(Self::Item {
+                    version,
+                    status,
+                    reason,
+                    device_id,
+                    server_time,
+                    protocol_version,
+                    sample_interval,
+                })
+            }
+            _ => Err(ConverterError::BytesConvertError(format!(
+                "Version does not exist: {}",
+                version
+            ))),
+        }
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, ConverterError> {
+    value
+        .parse()
+        .map_err(|_| ConverterError::BytesConvertError(format!("invalid {}: {}", name, value)))
+}
+
+/// What happened on an edge triggered sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Boot,
+    ContactOpened,
+    ContactClosed,
+    Motion,
+    ButtonPress,
+    Other(u8),
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> String {
+        match self {
+            Self::Boot => "boot".to_string(),
+            Self::ContactOpened => "contact_opened".to_string(),
+            Self::ContactClosed => "contact_closed".to_string(),
+            Self::Motion => "motion".to_string(),
+            Self::ButtonPress => "button_press".to_string(),
+            Self::Other(v) => format!("other_{}", v),
+        }
+    }
+}
+
+impl From<u8> for EventKind {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Boot,
+            1 => Self::ContactOpened,
+            2 => Self::ContactClosed,
+            3 => Self::Motion,
+            4 => Self::ButtonPress,
+            v => Self::Other(v),
+        }
+    }
+}
+
+impl From<EventKind> for u8 {
+    fn from(value: EventKind) -> Self {
+        match value {
+            EventKind::Boot => 0,
+            EventKind::ContactOpened => 1,
+            EventKind::ContactClosed => 2,
+            EventKind::Motion => 3,
+            EventKind::ButtonPress => 4,
+            EventKind::Other(v) => v,
+        }
+    }
+}
+
+/// A discrete event such as a door opening, sent once per edge rather than periodically.
+///
+/// Events are acknowledged by the server with an [`AckPacket`] carrying the same
+/// `sequence`, the client retransmits until it sees one.
+///
+/// Layout after the common header:
+///
+/// | bytes  | field                                    |
+/// |--------|------------------------------------------|
+/// | 8..16  | device timestamp, ms since epoch (u64 le)|
+/// | 16     | event kind                               |
+/// | 17..19 | channel (u16 le)                         |
+/// | 19     | 1 if a payload follows                   |
+/// | 20..22 | payload length (u16 le)                  |
+/// | 64..   | payload                                  |
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventPacket {
+    pub version: String,
+    pub sequence: u32,
+    pub kind: EventKind,
+    pub channel: u16,
+    /// Milliseconds since the unix epoch as seen by the device, 0 if the device has no clock.
+    pub timestamp: u64,
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Default for EventPacket {
+    fn default() -> Self {
+        Self {
+            version: "0.0".to_string(),
+            sequence: 0,
+            kind: EventKind::Boot,
+            channel: 0,
+            timestamp: 0,
+            payload: None,
+        }
+    }
+}
+
+impl Sendable for EventPacket {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        let payload = self.payload.as_deref().unwrap_or_default();
+        if HEADER_SIZE + payload.len() > BUFFER_SIZE {
+            return Err(ConverterError::BytesConvertError(format!(
+                "event payload too large: {} bytes",
+                payload.len()
+            )));
+        }
+
+        let mut bytes = vec![0u8; HEADER_SIZE + payload.len()];
+        write_header(&mut bytes, &self.version, PacketKind::Event, self.sequence)?;
+        bytes[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[16] = self.kind.into();
+        bytes[17..19].copy_from_slice(&self.channel.to_le_bytes());
+        bytes[19] = self.payload.is_some() as u8;
+        bytes[20..22].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        bytes[HEADER_SIZE..].copy_from_slice(payload);
+
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (version, kind, sequence) = read_header(bytes)?;
+        if kind != PacketKind::Event {
+            return Err(ConverterError::BytesConvertError(format!(
+                "expected event packet, got {:?}",
+                kind
+            )));
+        }
+
+        let timestamp = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let channel = u16::from_le_bytes(bytes[17..19].try_into().unwrap());
+        let payload_len = u16::from_le_bytes(bytes[20..22].try_into().unwrap()) as usize;
+        let payload = match bytes[19] {
+            0 => None,
+            _ => Some(
+                bytes
+                    .get(HEADER_SIZE..HEADER_SIZE + payload_len)
+                    .ok_or_else(|| {
+                        ConverterError::BytesConvertError(format!(
+                            "event payload truncated, expected {} bytes",
+                            payload_len
+                        ))
+                    })?
+                    .to_vec(),
+            ),
+        };
+
+        Ok(Self::Item {
+            version,
+            sequence,
+            kind: EventKind::from(bytes[16]),
+            channel,
+            timestamp,
+            payload,
+        })
+    }
+}
+
+/// Sent back to the sender of a packet once the server has stored it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AckPacket {
+    pub version: String,
+    pub sequence: u32,
+}
+
+impl AckPacket {
+    pub fn new(sequence: u32) -> Self {
+        Self {
+            version: "0.0".to_string(),
+            sequence,
+        }
+    }
+}
+
+impl Sendable for AckPacket {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        write_header(&mut bytes, &self.version, PacketKind::Ack, self.sequence)?;
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (version, kind, sequence) = read_header(bytes)?;
+        match kind {
+            PacketKind::Ack => Ok(Self::Item { version, sequence }),
+            _ => Err(ConverterError::BytesConvertError(format!(
+                "expected ack packet, got {:?}",
+                kind
+            ))),
+        }
+    }
+}
+
+/// Something the server asks a device to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// New delay between readings in milliseconds.
+    SetSampleInterval(u32),
+    SetLocation(String),
+    /// Make the device visible, e.g. by blinking its led.
+    Identify,
+    Reboot,
+    RequestHeartbeat,
+    RequestReRegistration,
+}
+
+impl Command {
+    fn code(&self) -> u8 {
+        match self {
+            Self::SetSampleInterval(_) => 0,
+            Self::SetLocation(_) => 1,
+            Self::Identify => 2,
+            Self::Reboot => 3,
+            Self::RequestHeartbeat => 4,
+            Self::RequestReRegistration => 5,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SetSampleInterval(_) => "set_sample_interval",
+            Self::SetLocation(_) => "set_location",
+            Self::Identify => "identify",
+            Self::Reboot => "reboot",
+            Self::RequestHeartbeat => "request_heartbeat",
+            Self::RequestReRegistration => "request_re_registration",
+        }
+    }
+
+    /// Builds a command from its [`Command::as_str`] name and textual argument.
+    pub fn parse(name: &str, argument: Option<&str>) -> Result<Self, ConverterError> {
+        let argument = |what: &str| {
+            argument.ok_or_else(|| {
+                ConverterError::BytesConvertError(format!("{} needs an argument", what))
+            })
+        };
+        match name {
+            "set_sample_interval" => Ok(Self::SetSampleInterval(parse_field(
+                argument(name)?,
+                "sample interval",
+            )?)),
+            "set_location" => Ok(Self::SetLocation(argument(name)?.to_string())),
+            "identify" => Ok(Self::Identify),
+            "reboot" => Ok(Self::Reboot),
+            "request_heartbeat" => Ok(Self::RequestHeartbeat),
+            "request_re_registration" => Ok(Self::RequestReRegistration),
+            _ => Err(ConverterError::BytesConvertError(format!(
+                "unknown command: {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// A [`Command`] sent from the server to a device over udp.
+///
+/// The command id travels in the sequence field of the header and is echoed back in the
+/// [`CommandAck`]. Layout after the common header:
+///
+/// | bytes  | field                          |
+/// |--------|--------------------------------|
+/// | 8      | command code                   |
+/// | 12..16 | numeric argument (u32 le)      |
+/// | 16..18 | text argument length (u16 le)  |
+/// | 64..   | text argument                  |
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandPacket {
+    pub version: String,
+    pub id: u32,
+    pub command: Command,
+}
+
+impl CommandPacket {
+    pub fn new(id: u32, command: Command) -> Self {
+        Self {
+            version: "0.0".to_string(),
+            id,
+            command,
+        }
+    }
+}
+
+impl Sendable for CommandPacket {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        let text = match &self.command {
+            Command::SetLocation(location) => location.as_bytes(),
+            _ => &[],
+        };
+        if HEADER_SIZE + text.len() > BUFFER_SIZE {
+            return Err(ConverterError::BytesConvertError(format!(
+                "command argument too large: {} bytes",
+                text.len()
+            )));
+        }
+
+        let mut bytes = vec![0u8; HEADER_SIZE + text.len()];
+        write_header(&mut bytes, &self.version, PacketKind::Command, self.id)?;
+        bytes[8] = self.command.code();
+        if let Command::SetSampleInterval(interval) = self.command {
+            bytes[12..16].copy_from_slice(&interval.to_le_bytes());
+        }
+        bytes[16..18].copy_from_slice(&(text.len() as u16).to_le_bytes());
+        bytes[HEADER_SIZE..].copy_from_slice(text);
+
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (version, kind, id) = read_header(bytes)?;
+        if kind != PacketKind::Command {
+            return Err(ConverterError::BytesConvertError(format!(
+                "expected command packet, got {:?}",
+                kind
+            )));
+        }
+
+        let number = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let text_len = u16::from_le_bytes(bytes[16..18].try_into().unwrap()) as usize;
+        let text = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + text_len)
+            .ok_or_else(|| {
+                ConverterError::BytesConvertError(format!(
+                    "command argument truncated, expected {} bytes",
+                    text_len
+                ))
+            })?;
+
+        let command = match bytes[8] {
+            0 => Command::SetSampleInterval(number),
+            1 => Command::SetLocation(text_from_bytes(text)?),
+            2 => Command::Identify,
+            3 => Command::Reboot,
+            4 => Command::RequestHeartbeat,
+            5 => Command::RequestReRegistration,
+            code => {
+                return Err(ConverterError::BytesConvertError(format!(
+                    "unknown command code: {}",
+                    code
+                )));
+            }
+        };
+
+        Ok(Self::Item {
+            version,
+            id,
+            command,
+        })
+    }
+}
+
+/// Outcome of a [`Command`] on the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandStatus {
+    Completed = 0,
+    Failed = 1,
+    Unsupported = 2,
+}
+
+impl CommandStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// Sent by the device once it has acted on a [`CommandPacket`].
+///
+/// The command id travels in the sequence field, the status is byte 8.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandAck {
+    pub version: String,
+    pub id: u32,
+    pub status: CommandStatus,
+}
+
+impl CommandAck {
+    pub fn new(id: u32, status: CommandStatus) -> Self {
+        Self {
+            version: "0.0".to_string(),
+            id,
+            status,
+        }
+    }
+}
+
+impl Sendable for CommandAck {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        write_header(&mut bytes, &self.version, PacketKind::CommandAck, self.id)?;
+        bytes[8] = self.status as u8;
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (version, kind, id) = read_header(bytes)?;
+        if kind != PacketKind::CommandAck {
+            return Err(ConverterError::BytesConvertError(format!(
+                "expected command ack, got {:?}",
+                kind
+            )));
+        }
+        let status = match bytes[8] {
+            0 => CommandStatus::Completed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::Unsupported,
+            code => {
+                return Err(ConverterError::BytesConvertError(format!(
+                    "unknown command status: {}",
+                    code
+                )));
+            }
+        };
+        Ok(Self::Item {
+            version,
+            id,
+            status,
+        })
+    }
+}
+
+/// Severity of a forwarded [`LogRecord`], numbered like the `log` crate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+}
+
+impl TryFrom<u8> for LogLevel {
+    type Error = ConverterError;
+
+    fn try_from(value: u8) -> Result<Self, ConverterError> {
+        match value {
+            1 => Ok(Self::Error),
+            2 => Ok(Self::Warn),
+            3 => Ok(Self::Info),
+            4 => Ok(Self::Debug),
+            5 => Ok(Self::Trace),
+            _ => Err(ConverterError::BytesConvertError(format!(
+                "unknown log level: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// A log line forwarded from a device.
+///
+/// Stored records are acknowledged with an [`AckPacket`] carrying the same `sequence`,
+/// the client keeps a record buffered until it sees one.
+///
+/// Layout after the common header:
+///
+/// | bytes  | field                                    |
+/// |--------|------------------------------------------|
+/// | 8..16  | device timestamp, ms since epoch (u64 le)|
+/// | 16     | level                                    |
+/// | 17..19 | target length (u16 le)                   |
+/// | 19..21 | message length (u16 le)                  |
+/// | 64..   | target followed by message               |
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub version: String,
+    pub sequence: u32,
+    pub level: LogLevel,
+    /// Milliseconds since the unix epoch as seen by the device, 0 if the device has no clock.
+    pub timestamp: u64,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Room for target and message together.
+    pub const MAX_TEXT: usize = BUFFER_SIZE - HEADER_SIZE;
+
+    /// Builds a record, shortening `message` so the record fits in one packet.
+    pub fn new(level: LogLevel, timestamp: u64, target: &str, message: &str) -> Self {
+        let target = truncate(target, Self::MAX_TEXT / 4);
+        let message = truncate(message, Self::MAX_TEXT - target.len());
+        Self {
+            version: "0.0".to_string(),
+            sequence: 0,
+            level,
+            timestamp,
+            target: target.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Cuts `text` to at most `max` bytes without splitting a character.
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+impl Sendable for LogRecord {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        let target = self.target.as_bytes();
+        let message = self.message.as_bytes();
+        if target.len() + message.len() > Self::MAX_TEXT {
+            return Err(ConverterError::BytesConvertError(format!(
+                "log record too large: {} bytes",
+                target.len() + message.len()
+            )));
+        }
+
+        let mut bytes = vec![0u8; HEADER_SIZE + target.len() + message.len()];
+        write_header(&mut bytes, &self.version, PacketKind::Log, self.sequence)?;
+        bytes[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[16] = self.level as u8;
+        bytes[17..19].copy_from_slice(&(target.len() as u16).to_le_bytes());
+        bytes[19..21].copy_from_slice(&(message.len() as u16).to_le_bytes());
+        bytes[HEADER_SIZE..HEADER_SIZE + target.len()].copy_from_slice(target);
+        bytes[HEADER_SIZE + target.len()..].copy_from_slice(message);
+
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (version, kind, sequence) = read_header(bytes)?;
+        if kind != PacketKind::Log {
+            return Err(ConverterError::BytesConvertError(format!(
+                "expected log record, got {:?}",
+                kind
+            )));
+        }
+
+        let timestamp = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let target_len = u16::from_le_bytes(bytes[17..19].try_into().unwrap()) as usize;
+        let message_len = u16::from_le_bytes(bytes[19..21].try_into().unwrap()) as usize;
+        let text = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + target_len + message_len)
+            .ok_or_else(|| {
+                ConverterError::BytesConvertError(format!(
+                    "log record truncated, expected {} bytes",
+                    target_len + message_len
+                ))
+            })?;
+
+        Ok(Self::Item {
+            version,
+            sequence,
+            level: LogLevel::try_from(bytes[16])?,
+            timestamp,
+            target: text_from_bytes(&text[..target_len])?,
+            message: text_from_bytes(&text[target_len..])?,
+        })
+    }
+}
+
+/// Any binary packet that can arrive on the udp socket.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Data(NetworkPacket),
+    Event(EventPacket),
+    Ack(AckPacket),
+    Command(CommandPacket),
+    CommandAck(CommandAck),
+    Log(LogRecord),
+}
+
+impl Sendable for Message {
+    type Item = Self;
+
+    fn to_bytes(self) -> Result<Vec<u8>, ConverterError> {
+        match self {
+            Self::Data(packet) => packet.to_bytes(),
+            Self::Event(packet) => packet.to_bytes(),
+            Self::Ack(packet) => packet.to_bytes(),
+            Self::Command(packet) => packet.to_bytes(),
+            Self::CommandAck(packet) => packet.to_bytes(),
+            Self::Log(record) => record.to_bytes(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self::Item, ConverterError> {
+        let (_, kind, _) = read_header(bytes)?;
+        match kind {
+            PacketKind::Data => NetworkPacket::from_bytes(bytes).map(Self::Data),
+            PacketKind::Event => EventPacket::from_bytes(bytes).map(Self::Event),
+            PacketKind::Ack => AckPacket::from_bytes(bytes).map(Self::Ack),
+            PacketKind::Command => CommandPacket::from_bytes(bytes).map(Self::Command),
+            PacketKind::CommandAck => CommandAck::from_bytes(bytes).map(Self::CommandAck),
+            PacketKind::Log => LogRecord::from_bytes(bytes).map(Self::Log),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,4 +1066,115 @@ mod tests {
         let parsed = NetworkPacket::from_bytes(&bytes).unwrap();
         assert_eq!(data, &parsed.data[0..2])
     }
+
+    #[test]
+    fn test_event_round_trip() {
+        let event = EventPacket {
+            sequence: 7,
+            kind: EventKind::ContactOpened,
+            channel: 2,
+            timestamp: 1_700_000_000_123,
+            payload: Some(vec![1, 2, 3]),
+            ..Default::default()
+        };
+        let bytes = event.clone().to_bytes().unwrap();
+        assert_eq!(Message::Event(event), Message::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_message_dispatches_on_kind() {
+        let bytes = create_thing(&vec![1.]).to_bytes().unwrap();
+        assert!(matches!(Message::from_bytes(&bytes), Ok(Message::Data(_))));
+
+        let bytes = AckPacket::new(3).to_bytes().unwrap();
+        assert_eq!(
+            Message::Ack(AckPacket::new(3)),
+            Message::from_bytes(&bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ack_request_round_trip() {
+        let np = NetworkPacket {
+            sequence: 42,
+            ack_requested: true,
+            timestamp: 1_700_000_000_123,
+            ..create_thing(&vec![1.5])
+        };
+        let parsed = NetworkPacket::from_bytes(&np.to_bytes().unwrap()).unwrap();
+        assert_eq!(42, parsed.sequence);
+        assert!(parsed.ack_requested);
+        assert_eq!(1_700_000_000_123, parsed.timestamp);
+    }
+
+    #[test]
+    fn test_registration_response_round_trip() {
+        let accepted =
+            RegistrationResponse::accepted(3, 1_700_000_000_000, "0.0".to_string(), 2000);
+        let parsed = RegistrationResponse::from_bytes(&accepted.clone().to_bytes().unwrap());
+        assert_eq!(accepted, parsed.unwrap());
+
+        let rejected = RegistrationResponse::rejected(
+            STATUS_BAD_REQUEST,
+            "missing field; units".to_string(),
+            1,
+        );
+        let parsed = RegistrationResponse::from_bytes(&rejected.to_bytes().unwrap()).unwrap();
+        assert!(!parsed.is_ok());
+        assert_eq!(None, parsed.device_id);
+        assert_eq!("missing field  units", parsed.reason);
+    }
+
+    #[test]
+    fn test_truncated_initialization_packet_is_an_error() {
+        assert!(InitializationPacket::from_bytes(b"0.0;kitchen").is_err());
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        for command in [
+            Command::SetSampleInterval(5000),
+            Command::SetLocation("attic".to_string()),
+            Command::Reboot,
+        ] {
+            let packet = CommandPacket::new(9, command);
+            let bytes = packet.clone().to_bytes().unwrap();
+            assert_eq!(
+                Message::Command(packet),
+                Message::from_bytes(&bytes).unwrap()
+            );
+        }
+
+        let ack = CommandAck::new(9, CommandStatus::Unsupported);
+        let bytes = ack.clone().to_bytes().unwrap();
+        assert_eq!(
+            Message::CommandAck(ack),
+            Message::from_bytes(&bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_command_parse() {
+        assert_eq!(
+            Command::SetSampleInterval(1000),
+            Command::parse("set_sample_interval", Some("1000")).unwrap()
+        );
+        assert!(Command::parse("set_location", None).is_err());
+        assert!(Command::parse("self_destruct", None).is_err());
+    }
+
+    #[test]
+    fn test_log_record_round_trip() {
+        let record = LogRecord::new(LogLevel::Warn, 12, "clients::wifi", "signal lost");
+        let bytes = record.clone().to_bytes().unwrap();
+        assert_eq!(Message::Log(record), Message::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_long_log_message_is_truncated() {
+        let message = "é".repeat(LogRecord::MAX_TEXT);
+        let record = LogRecord::new(LogLevel::Error, 0, "target", &message);
+        assert!(record.target.len() + record.message.len() <= LogRecord::MAX_TEXT);
+        assert!(record.to_bytes().unwrap().len() <= BUFFER_SIZE);
+    }
 }
@@ -0,0 +1,67 @@
+CREATE TABLE IF NOT EXISTS location (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT
+);
+
+CREATE TABLE IF NOT EXISTS data (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    location_id INTEGER NOT NULL,
+    timestamp TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    value FLOAT,
+    measurand TEXT,
+    units TEXT,
+    FOREIGN KEY (location_id) REFERENCES location(id)
+);
+
+CREATE TABLE IF NOT EXISTS event (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    location_id INTEGER NOT NULL,
+    timestamp TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    device_timestamp INTEGER,
+    kind TEXT NOT NULL,
+    channel INTEGER NOT NULL,
+    payload BLOB,
+    FOREIGN KEY (location_id) REFERENCES location(id)
+);
+
+CREATE TABLE IF NOT EXISTS command (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    argument TEXT,
+    status TEXT DEFAULT 'pending' NOT NULL,
+    created TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    sent TIMESTAMP,
+    completed TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS device_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    timestamp TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    device_timestamp INTEGER,
+    level TEXT NOT NULL,
+    target TEXT NOT NULL,
+    message TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS device_log_device_timestamp ON device_log (device_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS device (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    address TEXT NOT NULL UNIQUE,
+    version TEXT NOT NULL,
+    location TEXT NOT NULL,
+    data_map TEXT NOT NULL,
+    units TEXT NOT NULL,
+    measureands TEXT NOT NULL,
+    registered TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    updated TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL
+);
+
+INSERT INTO
+    location (name)
+SELECT
+    'kitchen'
+WHERE
+    NOT EXISTS (SELECT 1 FROM location WHERE name = 'kitchen');
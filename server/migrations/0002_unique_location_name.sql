@@ -0,0 +1,35 @@
+-- point rows at the oldest location of the same name before removing the duplicates
+UPDATE data
+SET
+    location_id = (
+        SELECT
+            MIN(other.id)
+        FROM
+            location AS this
+            JOIN location AS other ON other.name = this.name
+        WHERE
+            this.id = data.location_id
+    )
+WHERE
+    location_id IN (SELECT id FROM location WHERE name IS NOT NULL);
+
+UPDATE event
+SET
+    location_id = (
+        SELECT
+            MIN(other.id)
+        FROM
+            location AS this
+            JOIN location AS other ON other.name = this.name
+        WHERE
+            this.id = event.location_id
+    )
+WHERE
+    location_id IN (SELECT id FROM location WHERE name IS NOT NULL);
+
+DELETE FROM location
+WHERE
+    name IS NOT NULL
+    AND id NOT IN (SELECT MIN(id) FROM location GROUP BY name);
+
+CREATE UNIQUE INDEX IF NOT EXISTS location_name ON location (name);
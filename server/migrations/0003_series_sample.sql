@@ -0,0 +1,67 @@
+-- Readings were stored with their measurand and unit text repeated on every row. They
+-- now live in `sample`, pointing at a `series` that names what was measured where.
+
+CREATE TABLE series (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- 0 for readings stored before the sending device was recorded
+    device_id INTEGER NOT NULL,
+    location_id INTEGER NOT NULL,
+    measurand TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    created TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    UNIQUE (device_id, location_id, measurand, unit),
+    FOREIGN KEY (location_id) REFERENCES location(id)
+);
+
+CREATE INDEX series_location ON series (location_id);
+
+CREATE TABLE sample (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    series_id INTEGER NOT NULL,
+    timestamp TIMESTAMP DEFAULT (strftime('%s', 'now')) NOT NULL,
+    value FLOAT,
+    FOREIGN KEY (series_id) REFERENCES series(id)
+);
+
+-- range queries for one series are answered from the index alone
+CREATE INDEX sample_series_timestamp ON sample (series_id, timestamp, value);
+CREATE INDEX sample_timestamp ON sample (timestamp);
+
+INSERT INTO
+    series (device_id, location_id, measurand, unit)
+SELECT DISTINCT
+    0,
+    location_id,
+    IFNULL(measurand, ''),
+    IFNULL(units, '')
+FROM
+    data;
+
+INSERT INTO
+    sample (id, series_id, timestamp, value)
+SELECT
+    data.id,
+    series.id,
+    data.timestamp,
+    data.value
+FROM
+    data
+    JOIN series ON series.device_id = 0
+    AND series.location_id = data.location_id
+    AND series.measurand = IFNULL(data.measurand, '')
+    AND series.unit = IFNULL(data.units, '');
+
+DROP TABLE data;
+
+-- the old shape, so existing dashboards and queries keep working
+CREATE VIEW data AS
+SELECT
+    sample.id,
+    series.location_id,
+    sample.timestamp,
+    sample.value,
+    series.measurand,
+    series.unit AS units
+FROM
+    sample
+    JOIN series ON series.id = sample.series_id;
@@ -0,0 +1,99 @@
+-- Samples, events and device logs are timestamped in milliseconds since the unix epoch.
+-- SQLite can't change a column default in place, so the tables are rebuilt.
+
+DROP VIEW data;
+
+CREATE TABLE sample_ms (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    series_id INTEGER NOT NULL,
+    timestamp INTEGER DEFAULT (CAST(unixepoch('subsec') * 1000 AS INTEGER)) NOT NULL,
+    value FLOAT,
+    FOREIGN KEY (series_id) REFERENCES series(id)
+);
+
+INSERT INTO
+    sample_ms (id, series_id, timestamp, value)
+SELECT
+    id,
+    series_id,
+    timestamp * 1000,
+    value
+FROM
+    sample;
+
+DROP TABLE sample;
+ALTER TABLE sample_ms RENAME TO sample;
+
+CREATE INDEX sample_series_timestamp ON sample (series_id, timestamp, value);
+CREATE INDEX sample_timestamp ON sample (timestamp);
+
+-- `timestamp` stays in seconds so existing dashboards keep working
+CREATE VIEW data AS
+SELECT
+    sample.id,
+    series.location_id,
+    sample.timestamp / 1000 AS timestamp,
+    sample.timestamp AS timestamp_ms,
+    sample.value,
+    series.measurand,
+    series.unit AS units
+FROM
+    sample
+    JOIN series ON series.id = sample.series_id;
+
+CREATE TABLE event_ms (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    location_id INTEGER NOT NULL,
+    timestamp INTEGER DEFAULT (CAST(unixepoch('subsec') * 1000 AS INTEGER)) NOT NULL,
+    device_timestamp INTEGER,
+    kind TEXT NOT NULL,
+    channel INTEGER NOT NULL,
+    payload BLOB,
+    FOREIGN KEY (location_id) REFERENCES location(id)
+);
+
+INSERT INTO
+    event_ms (id, location_id, timestamp, device_timestamp, kind, channel, payload)
+SELECT
+    id,
+    location_id,
+    timestamp * 1000,
+    device_timestamp,
+    kind,
+    channel,
+    payload
+FROM
+    event;
+
+DROP TABLE event;
+ALTER TABLE event_ms RENAME TO event;
+
+CREATE INDEX event_location_timestamp ON event (location_id, timestamp);
+
+CREATE TABLE device_log_ms (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    timestamp INTEGER DEFAULT (CAST(unixepoch('subsec') * 1000 AS INTEGER)) NOT NULL,
+    device_timestamp INTEGER,
+    level TEXT NOT NULL,
+    target TEXT NOT NULL,
+    message TEXT NOT NULL
+);
+
+INSERT INTO
+    device_log_ms (id, device_id, timestamp, device_timestamp, level, target, message)
+SELECT
+    id,
+    device_id,
+    timestamp * 1000,
+    device_timestamp,
+    level,
+    target,
+    message
+FROM
+    device_log;
+
+DROP TABLE device_log;
+ALTER TABLE device_log_ms RENAME TO device_log;
+
+CREATE INDEX device_log_device_timestamp ON device_log (device_id, timestamp);
@@ -0,0 +1,50 @@
+-- Hourly and daily aggregates of `sample`, so raw rows can be pruned while long range
+-- panels keep their history. Buckets start at `bucket`, in ms since the unix epoch (UTC).
+
+CREATE TABLE rollup_hourly (
+    series_id INTEGER NOT NULL,
+    bucket INTEGER NOT NULL,
+    min FLOAT,
+    max FLOAT,
+    mean FLOAT,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (series_id, bucket),
+    FOREIGN KEY (series_id) REFERENCES series(id)
+) WITHOUT ROWID;
+
+CREATE TABLE rollup_daily (
+    series_id INTEGER NOT NULL,
+    bucket INTEGER NOT NULL,
+    min FLOAT,
+    max FLOAT,
+    mean FLOAT,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (series_id, bucket),
+    FOREIGN KEY (series_id) REFERENCES series(id)
+) WITHOUT ROWID;
+
+-- everything before `until` has been aggregated into the rollup called `name`
+CREATE TABLE rollup_progress (
+    name TEXT PRIMARY KEY NOT NULL,
+    until INTEGER NOT NULL
+);
+
+INSERT INTO rollup_progress (name, until) VALUES ('hourly', 0), ('daily', 0);
+
+-- How long rows are kept, in days. NULL keeps them forever. A series uses the most
+-- specific policy: location and measurand, then measurand, then location, then the
+-- policy without either.
+CREATE TABLE retention_policy (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    location TEXT,
+    measurand TEXT,
+    raw_days INTEGER,
+    hourly_days INTEGER,
+    daily_days INTEGER,
+    UNIQUE (location, measurand)
+);
+
+INSERT INTO
+    retention_policy (location, measurand, raw_days, hourly_days, daily_days)
+VALUES
+    (NULL, NULL, 30, 365, NULL);
@@ -0,0 +1,47 @@
+-- Rules stored readings are checked against. A rule watches every series matching its
+-- location, measurand and device, NULL matches any. By kind it fires when
+--   threshold: the value is above `above` or below `below`
+--   rate: the value changes faster than `above` or slower than `below` per minute
+--   absence: nothing was stored for `for_secs`
+-- once that held for `for_secs`, and resolves when the value is back inside the limits
+-- by `hysteresis`. Rules are read when the server starts.
+CREATE TABLE alert_rule (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL UNIQUE,
+    kind TEXT NOT NULL CHECK (kind IN ('threshold', 'rate', 'absence')),
+    location TEXT,
+    measurand TEXT,
+    device_id INTEGER,
+    above FLOAT,
+    below FLOAT,
+    hysteresis FLOAT NOT NULL DEFAULT 0 CHECK (hysteresis >= 0),
+    for_secs INTEGER NOT NULL DEFAULT 0 CHECK (for_secs >= 0),
+    enabled INTEGER NOT NULL DEFAULT 1
+);
+
+-- where each rule stands for each series it watches, so a restart doesn't fire again;
+-- `since` is when the state was entered, in ms since the unix epoch
+CREATE TABLE alert_state (
+    rule_id INTEGER NOT NULL,
+    device_id INTEGER NOT NULL,
+    measurand TEXT NOT NULL,
+    state TEXT NOT NULL CHECK (state IN ('ok', 'pending', 'firing')),
+    since INTEGER,
+    PRIMARY KEY (rule_id, device_id, measurand),
+    FOREIGN KEY (rule_id) REFERENCES alert_rule(id)
+) WITHOUT ROWID;
+
+-- every time an alert fired or resolved
+CREATE TABLE alert_event (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    rule_id INTEGER NOT NULL,
+    device_id INTEGER NOT NULL,
+    location TEXT NOT NULL,
+    measurand TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('firing', 'resolved')),
+    timestamp INTEGER NOT NULL,
+    value FLOAT,
+    FOREIGN KEY (rule_id) REFERENCES alert_rule(id)
+);
+
+CREATE INDEX alert_event_timestamp ON alert_event (timestamp);
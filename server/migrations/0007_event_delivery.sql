@@ -0,0 +1,11 @@
+-- Devices number their packets, starting over every time they register. `session`
+-- counts the registrations of a device, so device, session and sequence name a packet.
+ALTER TABLE device ADD COLUMN session INTEGER NOT NULL DEFAULT 0;
+
+-- events are sent until acknowledged, a lost ack must not store the event twice.
+-- events stored before this have no sequence and never conflict
+ALTER TABLE event ADD COLUMN device_id INTEGER;
+ALTER TABLE event ADD COLUMN session INTEGER;
+ALTER TABLE event ADD COLUMN sequence INTEGER;
+
+CREATE UNIQUE INDEX event_delivery ON event (device_id, session, sequence);
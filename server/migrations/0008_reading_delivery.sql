@@ -0,0 +1,10 @@
+-- Readings a device wants acknowledged are sent until the ack arrives. The packets
+-- stored last are remembered, so a repeat is acknowledged without storing it twice.
+-- Rows of older sessions and all but the last few sequences are removed as new ones
+-- are stored.
+CREATE TABLE reading_delivery (
+    device_id INTEGER NOT NULL,
+    session INTEGER NOT NULL,
+    sequence INTEGER NOT NULL,
+    PRIMARY KEY (device_id, session, sequence)
+) WITHOUT ROWID;
@@ -0,0 +1,22 @@
+-- Retention policies now come from the config file, which replaces them on start.
+-- Pruning is opt-in: the catch-all policy seeded by 0005 deleted raw samples after 30
+-- days on every installation, it goes unless someone changed it.
+DELETE FROM retention_policy
+WHERE
+    location IS NULL
+    AND measurand IS NULL
+    AND raw_days = 30
+    AND hourly_days = 365
+    AND daily_days IS NULL;
+
+-- UNIQUE (location, measurand) lets rows with NULLs repeat, keep the newest of each
+-- scope and index the scope with the NULLs folded in.
+DELETE FROM retention_policy
+WHERE id NOT IN (
+    SELECT MAX(id) FROM retention_policy GROUP BY location, measurand
+);
+
+CREATE UNIQUE INDEX retention_policy_scope ON retention_policy (
+    IFNULL(location, ''),
+    IFNULL(measurand, '')
+);
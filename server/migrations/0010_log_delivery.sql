@@ -0,0 +1,6 @@
+-- Log records are sent until acknowledged, a lost ack must not store a record twice.
+-- Records stored before this have no sequence and never conflict.
+ALTER TABLE device_log ADD COLUMN session INTEGER;
+ALTER TABLE device_log ADD COLUMN sequence INTEGER;
+
+CREATE UNIQUE INDEX device_log_delivery ON device_log (device_id, session, sequence);
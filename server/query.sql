@@ -1,13 +1,27 @@
 INSERT INTO
-    data (location_id, value, measurand, units)
+    series (device_id, location_id, measurand, unit)
 SELECT
+    ?1,
     location.id,
-    ?2,
     ?3,
     ?4
 FROM
     location
 WHERE
-    location.name = ?1
-LIMIT
-    1
+    location.name = ?2
+ON CONFLICT (device_id, location_id, measurand, unit) DO NOTHING;
+
+INSERT INTO
+    sample (series_id, timestamp, value)
+SELECT
+    series.id,
+    ?5,
+    ?6
+FROM
+    series
+    JOIN location ON location.id = series.location_id
+WHERE
+    series.device_id = ?1
+    AND location.name = ?2
+    AND series.measurand = ?3
+    AND series.unit = ?4;
@@ -0,0 +1,784 @@
+//! Alert rules checked against readings as they are stored.
+//!
+//! Rules live in the `alert_rule` table (see `migrations/0006_alerts.sql`), e.g.
+//! `INSERT INTO alert_rule (name, kind, location, measurand, above, hysteresis, for_secs)
+//! VALUES ('freezer warm', 'threshold', 'freezer', 'temperature', -10, 1, 300);`
+//! and are read when the server starts. Each rule is tracked separately for every series
+//! it matches. Where a rule stands is kept in `alert_state`, so an alert that fired
+//! before a restart doesn't fire again, and every time one fires or resolves is
+//! recorded in `alert_event` and sent to the notification sinks (see [`crate::notify`]).
+//! Notifications only go out once the new state is saved.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_sqlite::{
+    Pool,
+    rusqlite::{self, Connection, params},
+};
+use tokio::{
+    sync::{Mutex, oneshot},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    api::{self, SeriesFilter},
+    live::LiveReading,
+    notify::Notifier,
+};
+
+/// How often series are checked for missing readings.
+const ABSENCE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const MINUTE_MS: f64 = 60_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    /// The value leaves the limits.
+    Threshold,
+    /// The value changes faster than the limits allow, per minute.
+    Rate,
+    /// Nothing is stored for the rule's duration.
+    Absence,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Rule {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) kind: Kind,
+    pub(crate) filter: SeriesFilter,
+    pub(crate) above: Option<f64>,
+    pub(crate) below: Option<f64>,
+    /// How far back inside the limits a value has to be to resolve the alert.
+    pub(crate) hysteresis: f64,
+    /// How long the condition has to hold, in milliseconds.
+    pub(crate) duration: i64,
+}
+
+/// Where a rule stands for one series. Times are milliseconds since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum State {
+    Ok,
+    /// Outside the limits since then, but not for long enough.
+    Pending {
+        since: i64,
+    },
+    Firing {
+        since: i64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Status {
+    Firing,
+    Resolved,
+}
+
+/// An alert firing or resolving.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Transition {
+    pub(crate) rule: String,
+    pub(crate) status: Status,
+    pub(crate) device: u32,
+    pub(crate) location: String,
+    pub(crate) measurand: String,
+    pub(crate) timestamp: i64,
+    /// What was compared against the limits, the rate for rate rules. Absent for
+    /// absence rules.
+    pub(crate) value: Option<f64>,
+}
+
+/// A state to persist, with the transition that led to it if there was one.
+#[derive(Debug)]
+struct Change {
+    rule_id: i64,
+    device: u32,
+    measurand: String,
+    /// Restored when the change can't be saved, so the next reading tries again.
+    previous: State,
+    state: State,
+    transition: Option<Transition>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct Key {
+    rule_id: i64,
+    device: u32,
+    measurand: String,
+}
+
+#[derive(Debug, Clone)]
+struct Tracked {
+    location: String,
+    state: State,
+    /// Timestamp and value of the newest reading.
+    last: Option<(i64, f64)>,
+}
+
+/// The rules and where each stands, shared by every datagram handler.
+pub(crate) struct Alerts {
+    pool: Arc<Pool>,
+    notifier: Notifier,
+    rules: Vec<Rule>,
+    tracked: Mutex<HashMap<Key, Tracked>>,
+}
+
+impl Alerts {
+    /// Reads the enabled rules, their saved states and the newest reading of every
+    /// series they watch.
+    pub(crate) async fn load(
+        pool: Arc<Pool>,
+        notifier: Notifier,
+    ) -> Result<Self, async_sqlite::Error> {
+        let (rules, tracked) = pool
+            .conn(|conn| {
+                let rules = rules(conn)?;
+                let mut states = states(conn)?;
+                let mut tracked = HashMap::new();
+                for rule in &rules {
+                    for series in api::series(conn, &rule.filter)? {
+                        let key = Key {
+                            rule_id: rule.id,
+                            device: series.device,
+                            measurand: series.measurand,
+                        };
+                        let state = states.remove(&key).unwrap_or(State::Ok);
+                        let last = series.latest.map(|sample| (sample.timestamp, sample.value));
+                        let location = series.location;
+                        tracked.insert(
+                            key,
+                            Tracked {
+                                location,
+                                state,
+                                last,
+                            },
+                        );
+                    }
+                }
+                Ok((rules, tracked))
+            })
+            .await?;
+        Ok(Self {
+            pool,
+            notifier,
+            rules,
+            tracked: Mutex::new(tracked),
+        })
+    }
+
+    pub(crate) fn rules(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Checks a stored reading against every rule watching its series.
+    pub(crate) async fn observe(&self, reading: &LiveReading) -> Vec<Transition> {
+        let value = f64::from(reading.value);
+        let mut tracked = self.tracked.lock().await;
+        let changes = self
+            .rules
+            .iter()
+            .filter(|rule| rule.filter.matches(reading))
+            .filter_map(|rule| {
+                let key = Key {
+                    rule_id: rule.id,
+                    device: reading.device,
+                    measurand: reading.measurand.clone(),
+                };
+                let entry = tracked.entry(key.clone()).or_insert_with(|| Tracked {
+                    location: reading.location.clone(),
+                    state: State::Ok,
+                    last: None,
+                });
+                entry.location.clone_from(&reading.location);
+                let previous = entry.state;
+                let change = entry.observe(rule, &key, reading.timestamp, value);
+                entry.remember(reading.timestamp, value);
+                change.map(|(state, transition)| Change {
+                    rule_id: rule.id,
+                    device: key.device,
+                    measurand: key.measurand,
+                    previous,
+                    state,
+                    transition,
+                })
+            })
+            .collect::<Vec<_>>();
+        // saved before the lock is released, so states are written in the order they changed
+        self.save(&mut tracked, changes).await
+    }
+
+    /// Fires absence rules for series that went quiet before `now`.
+    pub(crate) async fn check_absence(&self, now: i64) -> Vec<Transition> {
+        let mut tracked = self.tracked.lock().await;
+        let changes = self
+            .rules
+            .iter()
+            .filter(|rule| rule.kind == Kind::Absence)
+            .flat_map(|rule| {
+                tracked
+                    .iter_mut()
+                    .filter(|(key, _)| key.rule_id == rule.id)
+                    .filter_map(|(key, entry)| {
+                        let previous = entry.state;
+                        let (state, transition) = entry.check_absence(rule, key, now)?;
+                        Some(Change {
+                            rule_id: rule.id,
+                            device: key.device,
+                            measurand: key.measurand.clone(),
+                            previous,
+                            state,
+                            transition: Some(transition),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        self.save(&mut tracked, changes).await
+    }
+
+    /// Persists `changes`, then logs and notifies their transitions, which are returned.
+    /// Changes that can't be saved are undone in `tracked` and nothing is notified, an
+    /// alert notified but not saved would notify again after a restart.
+    async fn save(
+        &self,
+        tracked: &mut HashMap<Key, Tracked>,
+        changes: Vec<Change>,
+    ) -> Vec<Transition> {
+        if changes.is_empty() {
+            return vec![];
+        }
+        let undo = changes
+            .iter()
+            .map(|change| {
+                let key = Key {
+                    rule_id: change.rule_id,
+                    device: change.device,
+                    measurand: change.measurand.clone(),
+                };
+                (key, change.previous)
+            })
+            .collect::<Vec<_>>();
+        let changes = match self
+            .pool
+            .conn_mut(move |conn| save(conn, &changes).map(|()| changes))
+            .await
+        {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!(error = %e, "unable to save alert states, trying again later");
+                for (key, previous) in undo {
+                    if let Some(entry) = tracked.get_mut(&key) {
+                        entry.state = previous;
+                    }
+                }
+                return vec![];
+            }
+        };
+        let transitions = changes
+            .into_iter()
+            .filter_map(|change| change.transition)
+            .collect::<Vec<_>>();
+        for transition in &transitions {
+            match transition.status {
+                Status::Firing => warn!(
+                    rule = transition.rule,
+                    device = transition.device,
+                    location = transition.location,
+                    measurand = transition.measurand,
+                    value = transition.value,
+                    "alert firing"
+                ),
+                Status::Resolved => info!(
+                    rule = transition.rule,
+                    device = transition.device,
+                    location = transition.location,
+                    measurand = transition.measurand,
+                    value = transition.value,
+                    "alert resolved"
+                ),
+            }
+            self.notifier.notify(transition);
+        }
+        transitions
+    }
+}
+
+impl Tracked {
+    /// Keeps the reading if it is the newest, a late one must not move rates and absence
+    /// checks back in time.
+    fn remember(&mut self, timestamp: i64, value: f64) {
+        if self
+            .last
+            .is_none_or(|(last_timestamp, _)| timestamp > last_timestamp)
+        {
+            self.last = Some((timestamp, value));
+        }
+    }
+
+    /// Moves the state on for a reading, returns the new state if it changed.
+    fn observe(
+        &mut self,
+        rule: &Rule,
+        key: &Key,
+        timestamp: i64,
+        value: f64,
+    ) -> Option<(State, Option<Transition>)> {
+        let compared = match rule.kind {
+            Kind::Threshold => value,
+            Kind::Rate => {
+                let (last_timestamp, last_value) = self.last?;
+                if timestamp <= last_timestamp {
+                    return None;
+                }
+                (value - last_value) * MINUTE_MS / (timestamp - last_timestamp) as f64
+            }
+            // any reading ends an absence
+            Kind::Absence => {
+                let State::Firing { .. } = self.state else {
+                    return None;
+                };
+                self.state = State::Ok;
+                let transition = self.transition(rule, key, Status::Resolved, timestamp, None);
+                return Some((State::Ok, Some(transition)));
+            }
+        };
+
+        let breached = rule.above.is_some_and(|above| compared > above)
+            || rule.below.is_some_and(|below| compared < below);
+        let recovered = rule
+            .above
+            .is_none_or(|above| compared <= above - rule.hysteresis)
+            && rule
+                .below
+                .is_none_or(|below| compared >= below + rule.hysteresis);
+        let next = step(self.state, breached, recovered, timestamp, rule.duration);
+        if next == self.state {
+            return None;
+        }
+
+        let status = match (self.state, next) {
+            (State::Firing { .. }, _) => Some(Status::Resolved),
+            (_, State::Firing { .. }) => Some(Status::Firing),
+            _ => None,
+        };
+        self.state = next;
+        let transition =
+            status.map(|status| self.transition(rule, key, status, timestamp, Some(compared)));
+        Some((next, transition))
+    }
+
+    /// Fires an absence rule if nothing was stored for its duration.
+    fn check_absence(&mut self, rule: &Rule, key: &Key, now: i64) -> Option<(State, Transition)> {
+        let (last_timestamp, _) = self.last?;
+        if self.state != State::Ok || now - last_timestamp < rule.duration {
+            return None;
+        }
+        self.state = State::Firing { since: now };
+        let transition = self.transition(rule, key, Status::Firing, now, None);
+        Some((self.state, transition))
+    }
+
+    fn transition(
+        &self,
+        rule: &Rule,
+        key: &Key,
+        status: Status,
+        timestamp: i64,
+        value: Option<f64>,
+    ) -> Transition {
+        Transition {
+            rule: rule.name.clone(),
+            status,
+            device: key.device,
+            location: self.location.clone(),
+            measurand: key.measurand.clone(),
+            timestamp,
+            value,
+        }
+    }
+}
+
+/// The state after a reading that `breached` the limits or `recovered` from them. A
+/// value between the two, inside the hysteresis, changes nothing.
+fn step(state: State, breached: bool, recovered: bool, timestamp: i64, duration: i64) -> State {
+    match state {
+        State::Ok if breached && duration == 0 => State::Firing { since: timestamp },
+        State::Ok if breached => State::Pending { since: timestamp },
+        State::Pending { .. } | State::Firing { .. } if recovered => State::Ok,
+        State::Pending { since } if breached && timestamp - since >= duration => {
+            State::Firing { since: timestamp }
+        }
+        state => state,
+    }
+}
+
+/// The running absence checks.
+pub(crate) struct AbsenceTask {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl AbsenceTask {
+    /// Lets a check in progress finish and stops the task.
+    pub(crate) async fn shutdown(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.handle.await {
+            error!(error = %e, "absence check task failed");
+        }
+    }
+}
+
+/// Checks every series for missing readings while absence rules exist.
+pub(crate) fn spawn(alerts: Arc<Alerts>) -> Option<AbsenceTask> {
+    if !alerts.rules.iter().any(|rule| rule.kind == Kind::Absence) {
+        return None;
+    }
+
+    let (stop, mut stopped) = oneshot::channel();
+    let mut interval = tokio::time::interval(ABSENCE_CHECK_INTERVAL);
+    let handle = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = interval.tick() => {
+                    alerts.check_absence(crate::now_millis() as i64).await;
+                }
+            }
+        }
+    });
+    Some(AbsenceTask { stop, handle })
+}
+
+/// The enabled rules, skipping any that can never fire.
+fn rules(conn: &Connection) -> rusqlite::Result<Vec<Rule>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, kind, location, measurand, device_id, above, below, hysteresis, for_secs
+        FROM alert_rule
+        WHERE enabled
+        ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let kind = match row.get::<_, String>(2)?.as_str() {
+            "rate" => Kind::Rate,
+            "absence" => Kind::Absence,
+            _ => Kind::Threshold,
+        };
+        Ok(Rule {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            kind,
+            filter: SeriesFilter {
+                location: row.get(3)?,
+                measurand: row.get(4)?,
+                device: row.get(5)?,
+            },
+            above: row.get(6)?,
+            below: row.get(7)?,
+            hysteresis: row.get(8)?,
+            duration: row.get::<_, i64>(9)? * 1000,
+        })
+    })?;
+
+    let mut rules = vec![];
+    for rule in rows {
+        let rule = rule?;
+        let usable = match rule.kind {
+            Kind::Threshold | Kind::Rate => rule.above.is_some() || rule.below.is_some(),
+            Kind::Absence => rule.duration > 0,
+        };
+        if usable {
+            rules.push(rule);
+        } else {
+            warn!(
+                rule = rule.name,
+                "alert rule has no limits or duration, ignoring it"
+            );
+        }
+    }
+    Ok(rules)
+}
+
+fn states(conn: &Connection) -> rusqlite::Result<HashMap<Key, State>> {
+    let mut stmt =
+        conn.prepare("SELECT rule_id, device_id, measurand, state, since FROM alert_state")?;
+    let rows = stmt.query_map([], |row| {
+        let key = Key {
+            rule_id: row.get(0)?,
+            device: row.get(1)?,
+            measurand: row.get(2)?,
+        };
+        let since: Option<i64> = row.get(4)?;
+        let state = match (row.get::<_, String>(3)?.as_str(), since) {
+            ("pending", Some(since)) => State::Pending { since },
+            ("firing", Some(since)) => State::Firing { since },
+            _ => State::Ok,
+        };
+        Ok((key, state))
+    })?;
+    rows.collect()
+}
+
+fn save(conn: &mut Connection, changes: &[Change]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    for change in changes {
+        let (state, since) = match change.state {
+            State::Ok => ("ok", None),
+            State::Pending { since } => ("pending", Some(since)),
+            State::Firing { since } => ("firing", Some(since)),
+        };
+        tx.execute(
+            "INSERT INTO
+                alert_state (rule_id, device_id, measurand, state, since)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (rule_id, device_id, measurand) DO UPDATE SET
+                state = excluded.state,
+                since = excluded.since",
+            params![
+                change.rule_id,
+                change.device,
+                change.measurand,
+                state,
+                since
+            ],
+        )?;
+        if let Some(transition) = &change.transition {
+            let status = match transition.status {
+                Status::Firing => "firing",
+                Status::Resolved => "resolved",
+            };
+            tx.execute(
+                "INSERT INTO
+                    alert_event (rule_id, device_id, location, measurand, status, timestamp, value)
+                VALUES
+                    (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    change.rule_id,
+                    transition.device,
+                    transition.location,
+                    transition.measurand,
+                    status,
+                    transition.timestamp,
+                    transition.value
+                ],
+            )?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: Kind, above: Option<f64>, duration: i64) -> Rule {
+        Rule {
+            id: 1,
+            name: "freezer warm".to_string(),
+            kind,
+            filter: SeriesFilter::default(),
+            above,
+            below: None,
+            hysteresis: 1.0,
+            duration,
+        }
+    }
+
+    fn key() -> Key {
+        Key {
+            rule_id: 1,
+            device: 3,
+            measurand: "temperature".to_string(),
+        }
+    }
+
+    /// Feeds `values` one second apart, returns the transitions they caused.
+    fn feed(tracked: &mut Tracked, rule: &Rule, values: &[f64]) -> Vec<Option<Status>> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let timestamp = i as i64 * 1000;
+                let change = tracked.observe(rule, &key(), timestamp, value);
+                tracked.remember(timestamp, value);
+                change.and_then(|(_, transition)| transition.map(|t| t.status))
+            })
+            .collect()
+    }
+
+    fn tracked() -> Tracked {
+        Tracked {
+            location: "freezer".to_string(),
+            state: State::Ok,
+            last: None,
+        }
+    }
+
+    #[test]
+    fn test_threshold_waits_for_duration_and_hysteresis() {
+        let rule = rule(Kind::Threshold, Some(-10.0), 2000);
+        let mut tracked = tracked();
+        let transitions = feed(
+            &mut tracked,
+            &rule,
+            // a short spike, then warm for long enough, then only just back under
+            &[-12.0, -9.0, -12.0, -9.0, -9.5, -9.0, -10.5, -11.5],
+        );
+        assert_eq!(
+            vec![
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(Status::Firing),
+                None,
+                Some(Status::Resolved)
+            ],
+            transitions
+        );
+    }
+
+    #[test]
+    fn test_rate_is_per_minute() {
+        let rule = rule(Kind::Rate, Some(60.0), 0);
+        let mut tracked = tracked();
+        // 1 per second is 60 per minute, 2 per second is too fast
+        let transitions = feed(&mut tracked, &rule, &[0.0, 1.0, 3.0, 4.0, 4.0]);
+        assert_eq!(
+            vec![
+                None,
+                None,
+                Some(Status::Firing),
+                None,
+                Some(Status::Resolved)
+            ],
+            transitions
+        );
+    }
+
+    #[test]
+    fn test_absence_fires_once_and_resolves_on_a_reading() {
+        let rule = rule(Kind::Absence, None, 60_000);
+        let mut tracked = tracked();
+        tracked.last = Some((0, -18.0));
+        assert!(tracked.check_absence(&rule, &key(), 59_999).is_none());
+        let (state, transition) = tracked.check_absence(&rule, &key(), 60_000).unwrap();
+        assert_eq!(State::Firing { since: 60_000 }, state);
+        assert_eq!(Status::Firing, transition.status);
+        assert!(tracked.check_absence(&rule, &key(), 120_000).is_none());
+
+        let (state, transition) = tracked.observe(&rule, &key(), 130_000, -18.0).unwrap();
+        assert_eq!(State::Ok, state);
+        assert_eq!(Some(Status::Resolved), transition.map(|t| t.status));
+    }
+
+    #[test]
+    fn test_states_survive_a_restart() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO alert_rule (name, kind, above) VALUES ('freezer warm', 'threshold', -10)",
+            [],
+        )
+        .unwrap();
+
+        let mut tracked = tracked();
+        let rule = &rules(&conn).unwrap()[0];
+        let (state, transition) = tracked.observe(rule, &key(), 5000, -5.0).unwrap();
+        let change = Change {
+            rule_id: rule.id,
+            device: 3,
+            measurand: "temperature".to_string(),
+            previous: State::Ok,
+            state,
+            transition,
+        };
+        save(&mut conn, &[change]).unwrap();
+
+        assert_eq!(
+            Some(&State::Firing { since: 5000 }),
+            states(&conn).unwrap().get(&key())
+        );
+        let events: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM alert_event WHERE status = 'firing'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(1, events);
+    }
+
+    #[test]
+    fn test_late_reading_does_not_move_last_back() {
+        let rate = rule(Kind::Rate, Some(60.0), 0);
+        let absence = rule(Kind::Absence, None, 60_000);
+        let mut tracked = tracked();
+        tracked.remember(10_000, 10.0);
+
+        assert!(tracked.observe(&rate, &key(), 1_000, 0.0).is_none());
+        tracked.remember(1_000, 0.0);
+        assert_eq!(Some((10_000, 10.0)), tracked.last);
+        // 1 per second since the newest reading, 6.6 per minute since the late one
+        assert!(tracked.observe(&rate, &key(), 11_000, 11.0).is_none());
+        assert!(tracked.check_absence(&absence, &key(), 65_000).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unsaved_transitions_are_not_notified() {
+        let pool = Arc::new(
+            async_sqlite::PoolBuilder::new()
+                .num_conns(1)
+                .open()
+                .await
+                .unwrap(),
+        );
+        pool.conn_mut(|conn| Ok(crate::db::migrate(conn)))
+            .await
+            .unwrap()
+            .unwrap();
+        pool.conn(|conn| {
+            conn.execute_batch(
+                "INSERT INTO alert_rule (name, kind, above) VALUES ('freezer warm', 'threshold', -10);
+                CREATE TRIGGER reject BEFORE INSERT ON alert_state
+                BEGIN
+                    SELECT RAISE(ABORT, 'rejected');
+                END;",
+            )
+        })
+        .await
+        .unwrap();
+        let notifier = Notifier::new(crate::notify::NotifySettings {
+            sinks: vec![],
+            title: String::new(),
+            message: String::new(),
+            retries: 0,
+            backoff: Duration::ZERO,
+            dry_run: true,
+        });
+        let alerts = Alerts::load(pool.clone(), notifier).await.unwrap();
+        let mut reading = LiveReading {
+            device: 3,
+            location: "freezer".to_string(),
+            measurand: "temperature".to_string(),
+            unit: "C".to_string(),
+            timestamp: 5000,
+            value: -5.0,
+        };
+
+        assert!(alerts.observe(&reading).await.is_empty());
+        pool.conn(|conn| conn.execute_batch("DROP TRIGGER reject;"))
+            .await
+            .unwrap();
+        // the state went back, so the next reading fires the alert again
+        reading.timestamp = 6000;
+        let transitions = alerts.observe(&reading).await;
+        assert_eq!(
+            vec![Status::Firing],
+            transitions.iter().map(|t| t.status).collect::<Vec<_>>()
+        );
+        let saved = pool.conn(states).await.unwrap();
+        assert_eq!(Some(&State::Firing { since: 6000 }), saved.get(&key()));
+    }
+}
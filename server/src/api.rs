@@ -0,0 +1,670 @@
+//! Read-only JSON API over the stored readings.
+//!
+//! | route                              | returns                                          |
+//! |------------------------------------|--------------------------------------------------|
+//! | `/api/locations`                   | every location                                   |
+//! | `/api/devices`                     | every registered device                          |
+//! | `/api/devices/{id}/logs`           | log records between `from` and `to`, at `level` or more severe |
+//! | `/api/series`                      | series with their latest sample, filterable by `location`, `measurand` and `device` |
+//! | `/api/series/{id}/readings`        | samples between `from` and `to`                  |
+//! | `/api/series/{id}/buckets`         | min, max and mean per `interval` between `from` and `to` |
+//!
+//! Times are milliseconds since the unix epoch. Ranges default to the last day and
+//! include `from` but not `to`. Queries run on the read-only pool so they never hold up
+//! ingest.
+
+use async_sqlite::rusqlite::{self, Connection, OptionalExtension, params};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    http::HttpState,
+    registry::split_list,
+    retention::{DAY_MS, HOUR_MS},
+};
+
+const DEFAULT_LIMIT: usize = 1000;
+pub(crate) const MAX_LIMIT: usize = 10_000;
+/// Levels devices log at, most severe first.
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Error, Debug)]
+pub(crate) enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Database error: {0}")]
+    Database(#[from] async_sqlite::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(ErrorBody {
+            error: self.to_string(),
+        });
+        (status, body).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct Location {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DeviceInfo {
+    pub(crate) id: u32,
+    pub(crate) address: String,
+    pub(crate) location: String,
+    pub(crate) version: String,
+    pub(crate) measurands: Vec<String>,
+    pub(crate) units: Vec<String>,
+    /// Seconds since the unix epoch.
+    pub(crate) registered: i64,
+    pub(crate) updated: i64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct DeviceLog {
+    pub(crate) timestamp: i64,
+    /// When the device logged it, absent for devices without a clock.
+    pub(crate) device_timestamp: Option<i64>,
+    pub(crate) level: String,
+    pub(crate) target: String,
+    pub(crate) message: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct Series {
+    pub(crate) id: i64,
+    pub(crate) device: u32,
+    pub(crate) location: String,
+    pub(crate) measurand: String,
+    pub(crate) unit: String,
+    /// Absent if every sample was pruned.
+    pub(crate) latest: Option<Sample>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct Sample {
+    pub(crate) timestamp: i64,
+    pub(crate) value: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct Bucket {
+    /// Start of the bucket.
+    pub(crate) timestamp: i64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) mean: f64,
+    pub(crate) count: i64,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub(crate) struct SeriesFilter {
+    pub(crate) location: Option<String>,
+    pub(crate) measurand: Option<String>,
+    pub(crate) device: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct Range {
+    pub(crate) from: Option<i64>,
+    pub(crate) to: Option<i64>,
+    pub(crate) limit: Option<usize>,
+    /// Bucket width in milliseconds, only used for buckets.
+    pub(crate) interval: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub(crate) struct LogFilter {
+    pub(crate) from: Option<i64>,
+    pub(crate) to: Option<i64>,
+    pub(crate) limit: Option<usize>,
+    pub(crate) level: Option<String>,
+}
+
+impl Range {
+    /// The bounds and limit with defaults filled in, checked for sense.
+    fn resolve(&self) -> Result<(i64, i64, usize), ApiError> {
+        let to = self.to.unwrap_or_else(|| crate::now_millis() as i64);
+        let from = self.from.unwrap_or(to.saturating_sub(DAY_MS));
+        if from >= to {
+            return Err(ApiError::BadRequest(format!(
+                "from ({}) must be before to ({})",
+                from, to
+            )));
+        }
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        Ok((from, to, limit))
+    }
+}
+
+pub(crate) fn router() -> Router<HttpState> {
+    Router::new()
+        .route("/api/locations", get(get_locations))
+        .route("/api/devices", get(get_devices))
+        .route("/api/devices/{id}/logs", get(get_logs))
+        .route("/api/series", get(get_series))
+        .route("/api/series/{id}/readings", get(get_readings))
+        .route("/api/series/{id}/buckets", get(get_buckets))
+}
+
+async fn get_locations(State(state): State<HttpState>) -> Result<Json<Vec<Location>>, ApiError> {
+    let locations = state.read_pool.conn(locations).await?;
+    Ok(Json(locations))
+}
+
+async fn get_devices(State(state): State<HttpState>) -> Result<Json<Vec<DeviceInfo>>, ApiError> {
+    let devices = state.read_pool.conn(devices).await?;
+    Ok(Json(devices))
+}
+
+async fn get_logs(
+    State(state): State<HttpState>,
+    Path(id): Path<u32>,
+    Query(filter): Query<LogFilter>,
+) -> Result<Json<Vec<DeviceLog>>, ApiError> {
+    let range = Range {
+        from: filter.from,
+        to: filter.to,
+        limit: filter.limit,
+        interval: None,
+    };
+    let (from, to, limit) = range.resolve()?;
+    let level = match filter.level.as_deref() {
+        None => LOG_LEVELS.len() - 1,
+        Some(level) => LOG_LEVELS
+            .iter()
+            .position(|known| known.eq_ignore_ascii_case(level))
+            .ok_or_else(|| {
+                ApiError::BadRequest(format!(
+                    "unknown level {}, expected one of {}",
+                    level,
+                    LOG_LEVELS.join(", ")
+                ))
+            })?,
+    };
+    let logs = state
+        .read_pool
+        .conn(move |conn| {
+            device_exists(conn, id)?
+                .then(|| logs(conn, id, from, to, level, limit))
+                .transpose()
+        })
+        .await?;
+    logs.map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no device {}", id)))
+}
+
+async fn get_series(
+    State(state): State<HttpState>,
+    Query(filter): Query<SeriesFilter>,
+) -> Result<Json<Vec<Series>>, ApiError> {
+    let series = state
+        .read_pool
+        .conn(move |conn| series(conn, &filter))
+        .await?;
+    Ok(Json(series))
+}
+
+async fn get_readings(
+    State(state): State<HttpState>,
+    Path(id): Path<i64>,
+    Query(range): Query<Range>,
+) -> Result<Json<Vec<Sample>>, ApiError> {
+    let (from, to, limit) = range.resolve()?;
+    let samples = state
+        .read_pool
+        .conn(move |conn| {
+            series_exists(conn, id)?
+                .then(|| readings(conn, id, from, to, limit))
+                .transpose()
+        })
+        .await?;
+    samples
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no series {}", id)))
+}
+
+async fn get_buckets(
+    State(state): State<HttpState>,
+    Path(id): Path<i64>,
+    Query(range): Query<Range>,
+) -> Result<Json<Vec<Bucket>>, ApiError> {
+    let (from, to, limit) = range.resolve()?;
+    let interval = range
+        .interval
+        .ok_or_else(|| ApiError::BadRequest("interval is required".to_string()))?;
+    if interval <= 0 {
+        return Err(ApiError::BadRequest(
+            "interval must be positive".to_string(),
+        ));
+    }
+    let buckets = state
+        .read_pool
+        .conn(move |conn| {
+            series_exists(conn, id)?
+                .then(|| buckets(conn, id, from, to, interval, limit))
+                .transpose()
+        })
+        .await?;
+    buckets
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no series {}", id)))
+}
+
+pub(crate) fn locations(conn: &Connection) -> rusqlite::Result<Vec<Location>> {
+    let mut stmt = conn.prepare_cached("SELECT id, name FROM location ORDER BY name")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Location {
+            id: row.get(0)?,
+            name: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub(crate) fn devices(conn: &Connection) -> rusqlite::Result<Vec<DeviceInfo>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, address, location, version, measureands, units, registered, updated
+        FROM device
+        ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(DeviceInfo {
+            id: row.get(0)?,
+            address: row.get(1)?,
+            // readings are filed under the lowercased location
+            location: row.get::<_, String>(2)?.to_lowercase(),
+            version: row.get(3)?,
+            measurands: split_list(row.get(4)?),
+            units: split_list(row.get(5)?),
+            registered: row.get(6)?,
+            updated: row.get(7)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Series matching `filter` with their newest sample.
+pub(crate) fn series(conn: &Connection, filter: &SeriesFilter) -> rusqlite::Result<Vec<Series>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT
+            series.id,
+            series.device_id,
+            location.name,
+            series.measurand,
+            series.unit,
+            sample.timestamp,
+            sample.value
+        FROM series
+            JOIN location ON location.id = series.location_id
+            LEFT JOIN sample ON sample.id = (
+                SELECT id
+                FROM sample
+                WHERE series_id = series.id
+                ORDER BY timestamp DESC, id DESC
+                LIMIT 1
+            )
+        WHERE
+            (?1 IS NULL OR location.name = lower(?1))
+            AND (?2 IS NULL OR series.measurand = ?2)
+            AND (?3 IS NULL OR series.device_id = ?3)
+        ORDER BY location.name, series.measurand, series.device_id",
+    )?;
+    let rows = stmt.query_map(
+        params![filter.location, filter.measurand, filter.device],
+        |row| {
+            let timestamp: Option<i64> = row.get(5)?;
+            let value: Option<f64> = row.get(6)?;
+            Ok(Series {
+                id: row.get(0)?,
+                device: row.get(1)?,
+                location: row.get(2)?,
+                measurand: row.get(3)?,
+                unit: row.get(4)?,
+                latest: timestamp
+                    .zip(value)
+                    .map(|(timestamp, value)| Sample { timestamp, value }),
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+fn device_exists(conn: &Connection, id: u32) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT 1 FROM device WHERE id = ?1", [id], |_| Ok(()))
+        .optional()
+        .map(|found| found.is_some())
+}
+
+/// Log records of a device in `from..to`, oldest first. `level` is the index of the
+/// least severe level in [`LOG_LEVELS`] to include.
+pub(crate) fn logs(
+    conn: &Connection,
+    device_id: u32,
+    from: i64,
+    to: i64,
+    level: usize,
+    limit: usize,
+) -> rusqlite::Result<Vec<DeviceLog>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT timestamp, device_timestamp, level, target, message
+        FROM device_log
+        WHERE
+            device_id = ?1
+            AND timestamp >= ?2
+            AND timestamp < ?3
+            AND CASE level
+                WHEN 'error' THEN 0
+                WHEN 'warn' THEN 1
+                WHEN 'info' THEN 2
+                WHEN 'debug' THEN 3
+                ELSE 4
+            END <= ?4
+        ORDER BY timestamp, id
+        LIMIT ?5",
+    )?;
+    let rows = stmt.query_map(params![device_id, from, to, level, limit], |row| {
+        Ok(DeviceLog {
+            timestamp: row.get(0)?,
+            device_timestamp: row.get(1)?,
+            level: row.get(2)?,
+            target: row.get(3)?,
+            message: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn series_exists(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT 1 FROM series WHERE id = ?1", [id], |_| Ok(()))
+        .optional()
+        .map(|found| found.is_some())
+}
+
+/// Raw samples of a series in `from..to`, oldest first.
+pub(crate) fn readings(
+    conn: &Connection,
+    series_id: i64,
+    from: i64,
+    to: i64,
+    limit: usize,
+) -> rusqlite::Result<Vec<Sample>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT timestamp, value
+        FROM sample
+        WHERE
+            series_id = ?1
+            AND timestamp >= ?2
+            AND timestamp < ?3
+            AND value IS NOT NULL
+        ORDER BY timestamp, id
+        LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(params![series_id, from, to, limit], |row| {
+        Ok(Sample {
+            timestamp: row.get(0)?,
+            value: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Aggregates a series into buckets of `interval` milliseconds aligned to the epoch.
+///
+/// Samples may already be pruned, so whole hour intervals are computed from the hourly
+/// rollups as far as those reach and from raw samples after that. Hours the range only
+/// covers in part, at either end, always come from raw samples.
+pub(crate) fn buckets(
+    conn: &Connection,
+    series_id: i64,
+    from: i64,
+    to: i64,
+    interval: i64,
+    limit: usize,
+) -> rusqlite::Result<Vec<Bucket>> {
+    let rolled_up_until = match interval % HOUR_MS {
+        0 => conn.query_row(
+            "SELECT until FROM rollup_progress WHERE name = 'hourly'",
+            [],
+            |row| row.get(0),
+        )?,
+        _ => i64::MIN,
+    };
+    let first_hour = from.saturating_add(HOUR_MS - 1).div_euclid(HOUR_MS) * HOUR_MS;
+    let last_hour = to.div_euclid(HOUR_MS) * HOUR_MS;
+    let rollups_until = last_hour.min(rolled_up_until).max(first_hour);
+
+    let mut stmt = conn.prepare_cached(
+        "WITH part (bucket, min, max, total, count) AS (
+            SELECT bucket / ?4 * ?4, min, max, mean * count, count
+            FROM rollup_hourly
+            WHERE
+                series_id = ?1
+                AND bucket >= ?5
+                AND bucket < ?6
+            UNION ALL
+            SELECT timestamp / ?4 * ?4, value, value, value, 1
+            FROM sample
+            WHERE
+                series_id = ?1
+                AND timestamp >= ?2
+                AND timestamp < ?3
+                AND (timestamp < ?5 OR timestamp >= ?6)
+                AND value IS NOT NULL
+        )
+        SELECT bucket, MIN(min), MAX(max), SUM(total) / SUM(count), SUM(count)
+        FROM part
+        GROUP BY bucket
+        ORDER BY bucket
+        LIMIT ?7",
+    )?;
+    let rows = stmt.query_map(
+        params![
+            series_id,
+            from,
+            to,
+            interval,
+            first_hour,
+            rollups_until,
+            limit
+        ],
+        |row| {
+            Ok(Bucket {
+                timestamp: row.get(0)?,
+                min: row.get(1)?,
+                max: row.get(2)?,
+                mean: row.get(3)?,
+                count: row.get(4)?,
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO location (name) VALUES ('hall');
+            INSERT INTO series (device_id, location_id, measurand, unit) VALUES
+                (1, 1, 'temperature', 'C'),
+                (2, 2, 'temperature', 'C');
+            INSERT INTO sample (series_id, timestamp, value) VALUES
+                (1, 1000, 20.0),
+                (1, 2000, 22.0),
+                (1, 3000, 24.0);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_series_carry_latest_sample() {
+        let conn = migrated();
+        let filter = SeriesFilter {
+            location: Some("Kitchen".to_string()),
+            ..Default::default()
+        };
+        let found = series(&conn, &filter).unwrap();
+        assert_eq!(1, found.len());
+        assert_eq!(
+            Some(Sample {
+                timestamp: 3000,
+                value: 24.0
+            }),
+            found[0].latest
+        );
+
+        // a series without samples is still listed
+        let all = series(&conn, &SeriesFilter::default()).unwrap();
+        assert_eq!(None, all[0].latest);
+    }
+
+    #[test]
+    fn test_range_rejects_extreme_bounds() {
+        let range = Range {
+            to: Some(i64::MIN),
+            ..Default::default()
+        };
+        assert!(matches!(range.resolve(), Err(ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_readings_respect_bounds_and_limit() {
+        let conn = migrated();
+        let found = readings(&conn, 1, 1000, 3000, 10).unwrap();
+        assert_eq!(vec![1000, 2000], timestamps(&found));
+        let found = readings(&conn, 1, 0, 10_000, 1).unwrap();
+        assert_eq!(vec![1000], timestamps(&found));
+    }
+
+    #[test]
+    fn test_buckets_combine_rollups_and_samples() {
+        let conn = migrated();
+        // an hour already rolled up whose samples were pruned
+        conn.execute_batch(&format!(
+            "INSERT INTO rollup_hourly (series_id, bucket, min, max, mean, count)
+                VALUES (1, {hour} * 2, 10.0, 30.0, 20.0, 4);
+            INSERT INTO sample (series_id, timestamp, value)
+                VALUES (1, {hour} * 3 + 5, 35.0);
+            UPDATE rollup_progress SET until = {hour} * 3 WHERE name = 'hourly';",
+            hour = HOUR_MS
+        ))
+        .unwrap();
+
+        let found = buckets(&conn, 1, 2 * HOUR_MS, 4 * HOUR_MS, 2 * HOUR_MS, 10).unwrap();
+        assert_eq!(
+            vec![Bucket {
+                timestamp: 2 * HOUR_MS,
+                min: 10.0,
+                max: 35.0,
+                mean: 23.0,
+                count: 5,
+            }],
+            found
+        );
+
+        // intervals that don't fit the rollups only see raw samples
+        let found = buckets(&conn, 1, 0, 5000, 2000, 10).unwrap();
+        assert_eq!(
+            vec![(0, 20.0), (2000, 23.0)],
+            found
+                .iter()
+                .map(|bucket| (bucket.timestamp, bucket.mean))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_logs_filter_by_level() {
+        let conn = migrated();
+        conn.execute_batch(
+            "INSERT INTO device_log (device_id, timestamp, device_timestamp, level, target, message)
+            VALUES
+                (1, 1000, NULL, 'error', 'main', 'sensor gone'),
+                (1, 2000, 1990, 'warn', 'wifi', 'weak signal'),
+                (1, 3000, NULL, 'info', 'main', 'booted'),
+                (2, 2000, NULL, 'error', 'main', 'other device');",
+        )
+        .unwrap();
+
+        let found = logs(&conn, 1, 0, 10_000, 1, 10).unwrap();
+        assert_eq!(
+            vec!["sensor gone", "weak signal"],
+            found
+                .iter()
+                .map(|log| log.message.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(1990), found[1].device_timestamp);
+        assert_eq!(3, logs(&conn, 1, 0, 10_000, 4, 10).unwrap().len());
+        assert_eq!(1, logs(&conn, 1, 2000, 3000, 4, 10).unwrap().len());
+    }
+
+    #[test]
+    fn test_buckets_read_partial_hours_from_samples() {
+        let conn = migrated();
+        conn.execute_batch(&format!(
+            "INSERT INTO rollup_hourly (series_id, bucket, min, max, mean, count) VALUES
+                (1, {hour} * 2, 10.0, 50.0, 30.0, 2),
+                (1, {hour} * 3, 40.0, 40.0, 40.0, 1),
+                (1, {hour} * 4, 60.0, 60.0, 60.0, 1);
+            INSERT INTO sample (series_id, timestamp, value) VALUES
+                (1, {hour} * 2 + {hour} / 6, 10.0),
+                (1, {hour} * 2 + {hour} * 2 / 3, 50.0),
+                (1, {hour} * 3 + 1, 40.0),
+                (1, {hour} * 4 + 1, 60.0);
+            UPDATE rollup_progress SET until = {hour} * 5 WHERE name = 'hourly';",
+            hour = HOUR_MS
+        ))
+        .unwrap();
+
+        // starts and ends halfway through a rolled up hour
+        let found = buckets(&conn, 1, HOUR_MS * 5 / 2, HOUR_MS * 9 / 2, HOUR_MS, 10).unwrap();
+        assert_eq!(
+            vec![
+                (2 * HOUR_MS, 50.0, 1),
+                (3 * HOUR_MS, 40.0, 1),
+                (4 * HOUR_MS, 60.0, 1)
+            ],
+            found
+                .iter()
+                .map(|bucket| (bucket.timestamp, bucket.mean, bucket.count))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn timestamps(samples: &[Sample]) -> Vec<i64> {
+        samples.iter().map(|sample| sample.timestamp).collect()
+    }
+}
@@ -0,0 +1,248 @@
+//! Online snapshots of the database through the SQLite backup API.
+//!
+//! Snapshots are consistent copies taken while the server keeps writing. They are named
+//! `db-<UTC time>.sqlite3` so sorting by name sorts them by age, and only the newest
+//! `keep` are kept. A snapshot is written under a temporary name and renamed once it is
+//! complete, so a crash never leaves a half written file among the snapshots.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_sqlite::{
+    Pool,
+    rusqlite::{self, Connection, DatabaseName, OpenFlags, backup::Backup},
+};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Pages copied per step, the source is unlocked between steps so writers can proceed.
+const PAGES_PER_STEP: i32 = 256;
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub(crate) enum BackupError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Database error: {0}")]
+    Pool(#[from] async_sqlite::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid snapshot {path}: {reason}")]
+    Invalid { path: PathBuf, reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BackupSettings {
+    pub(crate) dir: PathBuf,
+    /// How often a scheduled snapshot is taken, zero disables the schedule.
+    pub(crate) interval: Duration,
+    /// Snapshots kept after rotation.
+    pub(crate) keep: usize,
+}
+
+/// Takes a snapshot every `settings.interval` using a connection of `pool`.
+pub(crate) fn spawn(pool: Arc<Pool>, settings: BackupSettings) -> Option<JoinHandle<()>> {
+    if settings.interval.is_zero() {
+        return None;
+    }
+
+    let mut interval = tokio::time::interval(settings.interval);
+    Some(tokio::spawn(async move {
+        // the first tick completes immediately, don't snapshot on every restart
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let settings = settings.clone();
+            let result = pool
+                .conn(move |conn| Ok(snapshot(conn, &settings)))
+                .await
+                .map_err(BackupError::from)
+                .and_then(|result| result);
+            match result {
+                Ok(path) => info!(path = %path.display(), "wrote database snapshot"),
+                Err(e) => error!(error = %e, "unable to snapshot database"),
+            }
+        }
+    }))
+}
+
+/// Copies the database behind `source` into a new snapshot and rotates old ones.
+pub(crate) fn snapshot(
+    source: &Connection,
+    settings: &BackupSettings,
+) -> Result<PathBuf, BackupError> {
+    let path = write_snapshot(source, &settings.dir)?;
+    rotate(&settings.dir, settings.keep)?;
+    Ok(path)
+}
+
+fn write_snapshot(source: &Connection, dir: &Path) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(dir)?;
+
+    let stamp: String =
+        source.query_row("SELECT strftime('%Y%m%dT%H%M%fZ', 'now')", [], |row| {
+            row.get(0)
+        })?;
+    let path = dir.join(format!("db-{}.sqlite3", stamp));
+    let partial = dir.join(format!("db-{}.sqlite3.partial", stamp));
+
+    {
+        let mut destination = Connection::open(&partial)?;
+        Backup::new(source, &mut destination)?.run_to_completion(
+            PAGES_PER_STEP,
+            PAUSE_BETWEEN_STEPS,
+            None,
+        )?;
+        // a snapshot is a single self contained file
+        let _: String =
+            destination
+                .pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get(0))?;
+    }
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// Deletes all but the newest `keep` snapshots in `dir`.
+fn rotate(dir: &Path, keep: usize) -> Result<(), BackupError> {
+    let mut snapshots = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("db-") && name.ends_with(".sqlite3"))
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort();
+
+    let excess = snapshots.len().saturating_sub(keep);
+    for path in &snapshots[..excess] {
+        info!(path = %path.display(), "removing old snapshot");
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Checks that `path` is an intact database this server can migrate.
+pub(crate) fn validate(path: &Path) -> Result<(), BackupError> {
+    let invalid = |reason: String| BackupError::Invalid {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| invalid(e.to_string()))?;
+    let integrity: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(|e| invalid(e.to_string()))?;
+    if integrity != "ok" {
+        return Err(invalid(format!("integrity check failed: {}", integrity)));
+    }
+
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version == 0 || version > crate::db::schema_version() {
+        return Err(invalid(format!(
+            "schema version {} is not between 1 and {}",
+            version,
+            crate::db::schema_version()
+        )));
+    }
+    Ok(())
+}
+
+/// Replaces the contents of the database behind `live` with `snapshot`, after checking
+/// the snapshot and saving the current contents as a snapshot of their own.
+pub(crate) fn restore(
+    live: &mut Connection,
+    snapshot_path: &Path,
+    settings: &BackupSettings,
+) -> Result<PathBuf, BackupError> {
+    validate(snapshot_path)?;
+    let previous = write_snapshot(live, &settings.dir)?;
+    live.restore(DatabaseName::Main, snapshot_path, None::<fn(_)>)?;
+    // only rotate now, the snapshot being restored may have been the oldest
+    rotate(&settings.dir, settings.keep)?;
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str) -> BackupSettings {
+        let dir =
+            std::env::temp_dir().join(format!("server-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        BackupSettings {
+            dir,
+            interval: Duration::ZERO,
+            keep: 2,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_validates_and_restores() {
+        let settings = settings("restore");
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+
+        let path = snapshot(&conn, &settings).unwrap();
+        validate(&path).unwrap();
+
+        conn.execute("INSERT INTO location (name) VALUES ('hall')", [])
+            .unwrap();
+        restore(&mut conn, &path, &settings).unwrap();
+        let locations: usize = conn
+            .query_row("SELECT COUNT(*) FROM location", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(1, locations);
+
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_unmigrated_database() {
+        let settings = settings("invalid");
+        fs::create_dir_all(&settings.dir).unwrap();
+        let path = settings.dir.join("db-empty.sqlite3");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE t (id INTEGER)")
+            .unwrap();
+
+        assert!(matches!(validate(&path), Err(BackupError::Invalid { .. })));
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_keeps_newest() {
+        let settings = settings("rotate");
+        fs::create_dir_all(&settings.dir).unwrap();
+        for stamp in [
+            "20250101T000000.000Z",
+            "20250102T000000.000Z",
+            "20250103T000000.000Z",
+        ] {
+            fs::write(settings.dir.join(format!("db-{}.sqlite3", stamp)), b"").unwrap();
+        }
+
+        rotate(&settings.dir, settings.keep).unwrap();
+        let mut left = fs::read_dir(&settings.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            vec![
+                "db-20250102T000000.000Z.sqlite3",
+                "db-20250103T000000.000Z.sqlite3"
+            ],
+            left
+        );
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+}
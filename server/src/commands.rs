@@ -0,0 +1,323 @@
+//! Per device command queue backed by the `command` table.
+//!
+//! Commands are queued with `server queue <device> <kind> [argument]`, e.g.
+//! `server queue 1 set_sample_interval 5000`, and delivered as a udp reply the next time
+//! the device sends anything. A running server notices new commands within
+//! [`COMMAND_RETRY_SECS`].
+//!
+//! Devices remember which commands they executed only until they restart. Commands
+//! still unacknowledged when a device reports booting are marked `unconfirmed` and not
+//! sent again, a lost ack for a reboot would otherwise reboot the device once more.
+
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use async_sqlite::{
+    Pool,
+    rusqlite::{self, Connection},
+};
+use interface::{Command, CommandAck, CommandPacket, Sendable};
+use thiserror::Error;
+use tokio::{net::UdpSocket, sync::Mutex, time::Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::{ConfigError, SentDataResult, registry::AddressLookup, writer::WriteError};
+
+/// Sent but unacknowledged commands are delivered again after this many seconds.
+const COMMAND_RETRY_SECS: i64 = 10;
+
+#[derive(Error, Debug)]
+pub(crate) enum QueueError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Invalid command: {0}")]
+    Invalid(String),
+    #[error("No device with id {0}")]
+    UnknownDevice(u32),
+}
+
+/// Checks that `kind` and `argument` make a command the device can be sent and queues
+/// it. Returns the id of the command.
+pub(crate) fn queue(
+    conn: &Connection,
+    device_id: u32,
+    kind: &str,
+    argument: Option<&str>,
+) -> Result<i64, QueueError> {
+    Command::parse(kind, argument)
+        .and_then(|command| CommandPacket::new(0, command).to_bytes())
+        .map_err(|e| QueueError::Invalid(e.to_string()))?;
+
+    let known: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM device WHERE id = ?1)",
+        [device_id],
+        |row| row.get(0),
+    )?;
+    if !known {
+        return Err(QueueError::UnknownDevice(device_id));
+    }
+
+    conn.execute(
+        "INSERT INTO command (device_id, kind, argument) VALUES (?1, ?2, ?3)",
+        async_sqlite::rusqlite::params![device_id, kind, argument],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Devices that may have commands waiting, so datagrams from the others don't query
+/// the `command` table.
+///
+/// A device leaves the set once nothing is left to send it. The set is reloaded from the
+/// table every [`COMMAND_RETRY_SECS`], which picks up newly queued commands and those
+/// due to be sent again.
+pub(crate) struct PendingCommands {
+    state: Mutex<PendingState>,
+}
+
+struct PendingState {
+    devices: HashSet<u32>,
+    reloaded: Option<Instant>,
+}
+
+impl PendingCommands {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(PendingState {
+                devices: HashSet::new(),
+                reloaded: None,
+            }),
+        }
+    }
+
+    /// Whether `device_id` may have commands waiting, reloading the set when it is due.
+    async fn contains(&self, pool: &Pool, device_id: u32) -> bool {
+        let reload = {
+            let mut state = self.state.lock().await;
+            let due = state.reloaded.is_none_or(|reloaded| {
+                reloaded.elapsed() >= Duration::from_secs(COMMAND_RETRY_SECS as u64)
+            });
+            if due {
+                state.reloaded = Some(Instant::now());
+            }
+            due
+        };
+
+        if reload {
+            let devices = pool
+                .conn(|conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT DISTINCT device_id
+                        FROM command
+                        WHERE status IN ('pending', 'sent')",
+                    )?;
+                    let rows = stmt.query_map([], |row| row.get(0))?;
+                    rows.collect::<Result<HashSet<u32>, _>>()
+                })
+                .await;
+            match devices {
+                Ok(devices) => {
+                    debug!(devices = devices.len(), "reloaded devices with commands");
+                    self.state.lock().await.devices = devices;
+                }
+                Err(e) => error!(error = %e, "unable to load devices with commands"),
+            }
+        }
+
+        self.state.lock().await.devices.contains(&device_id)
+    }
+
+    async fn remove(&self, device_id: u32) {
+        self.state.lock().await.devices.remove(&device_id);
+    }
+}
+
+/// Sends every pending command for the device at `socket_addr`.
+pub async fn deliver(
+    socket_addr: &SocketAddr,
+    pool: &Pool,
+    address_lookup: &AddressLookup,
+    pending_commands: &PendingCommands,
+    sock: &UdpSocket,
+) {
+    let device_id = {
+        let address_lookup_guard = address_lookup.lock().await;
+        match address_lookup_guard.get(&socket_addr.ip()) {
+            Some(device) => device.id,
+            None => return,
+        }
+    };
+    if !pending_commands.contains(pool, device_id).await {
+        return;
+    }
+
+    let pending = match pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, kind, argument
+                FROM command
+                WHERE
+                    device_id = ?1
+                    AND (
+                        status = 'pending'
+                        OR (status = 'sent' AND sent < strftime('%s', 'now') - ?2)
+                    )
+                ORDER BY id",
+            )?;
+            let rows = stmt.query_map(
+                async_sqlite::rusqlite::params![device_id, COMMAND_RETRY_SECS],
+                |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                    ))
+                },
+            )?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await
+    {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!(error = %e, device_id, "unable to load commands");
+            return;
+        }
+    };
+    // whatever was sent waits for its ack until the next reload
+    if pending.is_empty() {
+        pending_commands.remove(device_id).await;
+    }
+
+    for (id, kind, argument) in pending {
+        let status = match Command::parse(&kind, argument.as_deref()) {
+            Ok(command) => match CommandPacket::new(id, command).to_bytes() {
+                Ok(bytes) => match sock.send_to(&bytes, socket_addr).await {
+                    Ok(_) => {
+                        info!(command = id, kind = %kind, "sent command");
+                        "sent"
+                    }
+                    Err(e) => {
+                        warn!(error = %e, command = id, "unable to send command");
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    error!(error = %e, command = id, "unable to encode command");
+                    "invalid"
+                }
+            },
+            Err(e) => {
+                warn!(error = %e, command = id, "invalid command");
+                "invalid"
+            }
+        };
+
+        if let Err(e) = pool
+            .conn(move |conn| {
+                conn.execute(
+                    "UPDATE command SET status = ?2, sent = strftime('%s', 'now') WHERE id = ?1",
+                    async_sqlite::rusqlite::params![id, status],
+                )
+            })
+            .await
+        {
+            error!(error = %e, command = id, "unable to update command");
+        }
+    }
+}
+
+/// Records the outcome a device reported for one of its commands.
+pub async fn handle_ack(
+    socket_addr: &SocketAddr,
+    pool: &Pool,
+    ack: CommandAck,
+    address_lookup: &AddressLookup,
+) -> SentDataResult<(), WriteError, ConfigError> {
+    let device_id = {
+        let address_lookup_guard = address_lookup.lock().await;
+        match address_lookup_guard.get(&socket_addr.ip()) {
+            Some(device) => device.id,
+            None => {
+                return SentDataResult::CfgErr(ConfigError::NotConfigured(socket_addr.to_string()));
+            }
+        }
+    };
+
+    info!(
+        command = ack.id,
+        status = ack.status.as_str(),
+        "command acknowledged"
+    );
+
+    match pool
+        .conn(move |conn| {
+            conn.execute(
+                "UPDATE command
+                SET status = ?3, completed = strftime('%s', 'now')
+                WHERE id = ?1 AND device_id = ?2",
+                async_sqlite::rusqlite::params![ack.id, device_id, ack.status.as_str()],
+            )
+        })
+        .await
+    {
+        Ok(rows) => {
+            if rows != 1 {
+                warn!(command = ack.id, "ack for unknown command");
+            }
+            SentDataResult::Ok(())
+        }
+        Err(e) => SentDataResult::Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_valid_commands_for_known_devices_are_queued() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO device (address, version, location, data_map, units, measureands)
+            VALUES ('127.0.0.1', '0.0', 'kitchen', '', '', '')",
+            [],
+        )
+        .unwrap();
+
+        let id = queue(&conn, 1, "set_sample_interval", Some("5000")).unwrap();
+        assert_eq!(1, id);
+        assert!(matches!(
+            queue(&conn, 1, "self_destruct", None),
+            Err(QueueError::Invalid(_))
+        ));
+        assert!(matches!(
+            queue(&conn, 1, "set_sample_interval", Some("soon")),
+            Err(QueueError::Invalid(_))
+        ));
+        assert!(matches!(
+            queue(&conn, 1, "set_location", None),
+            Err(QueueError::Invalid(_))
+        ));
+        assert!(matches!(
+            queue(&conn, 2, "reboot", None),
+            Err(QueueError::UnknownDevice(2))
+        ));
+
+        let queued: (u32, String, Option<String>, String) = conn
+            .query_row(
+                "SELECT device_id, kind, argument, status FROM command",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (
+                1,
+                "set_sample_interval".to_string(),
+                Some("5000".to_string()),
+                "pending".to_string()
+            ),
+            queued
+        );
+    }
+}
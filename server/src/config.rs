@@ -0,0 +1,756 @@
+//! Server settings.
+//!
+//! Each setting is taken from the first of these that has it: a command line flag, an
+//! environment variable (or `.env` file), the TOML config file, the built in default.
+//! `server.example.toml` lists every key of the file. Everything is validated once at
+//! startup so a bad value stops the server with a message instead of a panic later on.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use async_sqlite::JournalMode;
+use clap::{Parser, Subcommand, ValueEnum};
+use interface::BUFFER_SIZE;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    backup::BackupSettings,
+    db::{self, DbSettings},
+    notify::{NotifySettings, SinkSettings},
+    retention::{RetentionPolicy, RetentionSettings},
+    writer::BatchSettings,
+};
+
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+/// Kept apart from the sources, compose mounts the directory into Grafana. A database
+/// still at [`db::LEGACY_DB_PATH`] is moved here on start.
+const DEFAULT_DB_PATH: &str = "../data/db.sqlite3";
+const DEFAULT_SAMPLE_INTERVAL_MS: u32 = 2000;
+const DEFAULT_INGEST_QUEUE_SIZE: usize = 1024;
+const DEFAULT_INGEST_BATCH_SIZE: usize = 256;
+const DEFAULT_INGEST_BATCH_WINDOW_MS: u64 = 100;
+const DEFAULT_DB_BUSY_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_DB_CHECKPOINT_INTERVAL_SECS: u64 = 300;
+const DEFAULT_DB_POOL_SIZE: usize = 2;
+const DEFAULT_DB_READ_CONNECTIONS: usize = 4;
+const DEFAULT_MAX_CLOCK_SKEW_MS: u64 = 60_000;
+const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_BACKUP_DIR: &str = "backups";
+const DEFAULT_BACKUP_INTERVAL_SECS: u64 = 24 * 3600;
+const DEFAULT_BACKUP_KEEP: usize = 7;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_NOTIFY_TITLE: &str = "{rule} {status}";
+const DEFAULT_NOTIFY_MESSAGE: &str =
+    "{rule} is {status}: {measurand} in {location} is {value} (device {device})";
+const DEFAULT_NOTIFY_RETRIES: u32 = 3;
+const DEFAULT_NOTIFY_RETRY_BACKOFF_MS: u64 = 1000;
+
+#[derive(Error, Debug)]
+pub(crate) enum SettingsError {
+    #[error("Unable to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    /// One human readable line per event.
+    Text,
+    /// One JSON object per line, for shipping logs elsewhere.
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub(crate) udp_addr: SocketAddr,
+    pub(crate) tcp_addr: SocketAddr,
+    /// Where `/metrics` and the API are served, no HTTP server is started if unset.
+    pub(crate) http_addr: Option<SocketAddr>,
+    /// Size of the buffer datagrams are received into, longer datagrams are cut off.
+    pub(crate) buffer_size: usize,
+    pub(crate) log_level: LogLevel,
+    pub(crate) log_format: LogFormat,
+    /// How long a shutdown waits for requests in flight before abandoning them.
+    pub(crate) shutdown_timeout: Duration,
+    /// Delay between readings handed to devices when they register, in milliseconds.
+    pub(crate) sample_interval: u32,
+    /// Create unknown locations when a device registers with one.
+    pub(crate) auto_create_locations: bool,
+    /// If set, devices may only register with these locations.
+    pub(crate) location_allowlist: Option<HashSet<String>>,
+    /// Device timestamps further than this from the server's clock are replaced by the
+    /// time the packet arrived.
+    pub(crate) max_clock_skew: Duration,
+    pub(crate) batch: BatchSettings,
+    /// Raw bytes of rejected datagrams are written here when set.
+    pub(crate) quarantine_dir: Option<PathBuf>,
+    /// Export the latest value of every series as a metric.
+    pub(crate) series_metrics: bool,
+    pub(crate) db: DbSettings,
+    pub(crate) retention: RetentionSettings,
+    pub(crate) backup: BackupSettings,
+    pub(crate) notifications: NotifySettings,
+}
+
+/// Collects sensor readings from devices on the local network.
+#[derive(Parser, Debug, Default)]
+#[command(version)]
+pub(crate) struct Cli {
+    /// TOML file to read settings from, used if it exists when not given.
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+
+    /// Address both listeners bind to, `::` listens on IPv6 and IPv4.
+    #[arg(long, env = "BIND_ADDRESS")]
+    bind: Option<IpAddr>,
+    #[arg(long, env = "UDP_PORT")]
+    udp_port: Option<u16>,
+    #[arg(long, env = "TCP_PORT")]
+    tcp_port: Option<u16>,
+    /// Port of the HTTP server for metrics and the API, off unless set.
+    #[arg(long, env = "HTTP_PORT")]
+    http_port: Option<u16>,
+    /// Receive buffer for datagrams in bytes.
+    #[arg(long, env = "BUFFER_SIZE")]
+    buffer_size: Option<usize>,
+    /// Overridden per module by `RUST_LOG` directives if that is set.
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<LogLevel>,
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "SAMPLE_INTERVAL_MS")]
+    sample_interval_ms: Option<u32>,
+    #[arg(long, env = "MAX_CLOCK_SKEW_MS")]
+    max_clock_skew_ms: Option<u64>,
+    #[arg(long, env = "AUTO_CREATE_LOCATIONS", value_name = "BOOL")]
+    auto_create_locations: Option<bool>,
+    /// Comma separated locations devices may register with.
+    #[arg(long, env = "LOCATION_ALLOWLIST", value_delimiter = ',')]
+    location_allowlist: Option<Vec<String>>,
+
+    #[arg(long = "database", env = "DB_PATH")]
+    db_path: Option<String>,
+    /// One of wal, delete, truncate or persist.
+    #[arg(long, env = "DB_JOURNAL_MODE")]
+    journal_mode: Option<String>,
+    /// One of off, normal, full or extra.
+    #[arg(long, env = "DB_SYNCHRONOUS")]
+    synchronous: Option<String>,
+    #[arg(long, env = "DB_BUSY_TIMEOUT_MS")]
+    busy_timeout_ms: Option<u64>,
+    #[arg(long, env = "DB_CHECKPOINT_INTERVAL_SECS")]
+    checkpoint_interval_secs: Option<u64>,
+    /// Connections used for writing.
+    #[arg(long, env = "DB_POOL_SIZE")]
+    pool_size: Option<usize>,
+    #[arg(long, env = "DB_READ_CONNECTIONS")]
+    read_connections: Option<usize>,
+
+    #[arg(long, env = "INGEST_QUEUE_SIZE")]
+    ingest_queue_size: Option<usize>,
+    #[arg(long, env = "INGEST_BATCH_SIZE")]
+    ingest_batch_size: Option<usize>,
+    #[arg(long, env = "INGEST_BATCH_WINDOW_MS")]
+    ingest_batch_window_ms: Option<u64>,
+    /// Directory to keep the raw bytes of rejected datagrams in.
+    #[arg(long, env = "INGEST_QUARANTINE_DIR")]
+    quarantine_dir: Option<PathBuf>,
+
+    /// Export the latest value of every series on `/metrics`.
+    #[arg(long, env = "METRICS_SERIES", value_name = "BOOL")]
+    series_metrics: Option<bool>,
+
+    #[arg(long = "retention", env = "RETENTION_ENABLED", value_name = "BOOL")]
+    retention_enabled: Option<bool>,
+    #[arg(long, env = "RETENTION_INTERVAL_SECS")]
+    retention_interval_secs: Option<u64>,
+
+    #[arg(long = "backups", env = "BACKUP_ENABLED", value_name = "BOOL")]
+    backup_enabled: Option<bool>,
+    #[arg(long, env = "BACKUP_DIR")]
+    backup_dir: Option<PathBuf>,
+    #[arg(long, env = "BACKUP_INTERVAL_SECS")]
+    backup_interval_secs: Option<u64>,
+    #[arg(long, env = "BACKUP_KEEP")]
+    backup_keep: Option<usize>,
+
+    /// Log alert notifications instead of sending them.
+    #[arg(long, env = "NOTIFY_DRY_RUN", value_name = "BOOL")]
+    notify_dry_run: Option<bool>,
+    #[arg(long, env = "NOTIFY_RETRIES")]
+    notify_retries: Option<u32>,
+    #[arg(long, env = "NOTIFY_RETRY_BACKOFF_MS")]
+    notify_retry_backoff_ms: Option<u64>,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum Command {
+    /// Run the server, the default.
+    Serve,
+    /// Write a snapshot of the database to the backup directory.
+    Backup,
+    /// Replace the database with a snapshot. Restart a running server afterwards so it
+    /// reloads its devices.
+    Restore { snapshot: PathBuf },
+    /// Write Grafana provisioning with a dashboard for every registered device to `dir`.
+    Dashboards {
+        dir: PathBuf,
+        /// The server's Grafana endpoint as Grafana reaches it, by default
+        /// `http://host.docker.internal:<http port>/grafana`.
+        #[arg(long)]
+        url: Option<String>,
+    },
+    /// Queue a command for a device, it is sent the next time the device sends anything.
+    Queue {
+        /// The id of the device.
+        device: u32,
+        /// One of set_sample_interval, set_location, identify, reboot, request_heartbeat
+        /// and request_re_registration.
+        kind: String,
+        argument: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    shutdown_timeout_secs: Option<u64>,
+    network: NetworkSection,
+    devices: DevicesSection,
+    database: DatabaseSection,
+    ingest: IngestSection,
+    metrics: MetricsSection,
+    retention: RetentionSection,
+    backup: BackupSection,
+    notifications: NotificationsSection,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct NetworkSection {
+    bind: Option<IpAddr>,
+    udp_port: Option<u16>,
+    tcp_port: Option<u16>,
+    http_port: Option<u16>,
+    buffer_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DevicesSection {
+    sample_interval_ms: Option<u32>,
+    max_clock_skew_ms: Option<u64>,
+    auto_create_locations: Option<bool>,
+    location_allowlist: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    path: Option<String>,
+    journal_mode: Option<String>,
+    synchronous: Option<String>,
+    busy_timeout_ms: Option<u64>,
+    checkpoint_interval_secs: Option<u64>,
+    pool_size: Option<usize>,
+    read_connections: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct IngestSection {
+    queue_size: Option<usize>,
+    batch_size: Option<usize>,
+    batch_window_ms: Option<u64>,
+    quarantine_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsSection {
+    series: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RetentionSection {
+    enabled: Option<bool>,
+    interval_secs: Option<u64>,
+    policies: Vec<RetentionPolicy>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct NotificationsSection {
+    dry_run: Option<bool>,
+    retries: Option<u32>,
+    retry_backoff_ms: Option<u64>,
+    title: Option<String>,
+    message: Option<String>,
+    sinks: Vec<SinkSettings>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct BackupSection {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    interval_secs: Option<u64>,
+    keep: Option<usize>,
+}
+
+impl Config {
+    /// Reads the command line, environment and config file.
+    pub(crate) fn load() -> Result<(Self, Command), SettingsError> {
+        let cli = Cli::parse();
+        let file = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(DEFAULT_CONFIG_FILE.as_ref())?
+            }
+            None => FileConfig::default(),
+        };
+        let command = cli.command.clone().unwrap_or(Command::Serve);
+        Ok((Self::merge(cli, file)?, command))
+    }
+
+    fn merge(cli: Cli, file: FileConfig) -> Result<Self, SettingsError> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        let bind = cli
+            .bind
+            .or(file.network.bind)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let udp_port = cli.udp_port.or(file.network.udp_port);
+        let tcp_port = cli.tcp_port.or(file.network.tcp_port);
+        check(
+            udp_port.is_some(),
+            "udp port is not set (--udp-port, UDP_PORT or network.udp_port)",
+        );
+        check(
+            tcp_port.is_some(),
+            "tcp port is not set (--tcp-port, TCP_PORT or network.tcp_port)",
+        );
+        let buffer_size = cli
+            .buffer_size
+            .or(file.network.buffer_size)
+            .unwrap_or(BUFFER_SIZE);
+        check(
+            buffer_size >= BUFFER_SIZE,
+            &format!("buffer size must be at least {} bytes", BUFFER_SIZE),
+        );
+
+        let sample_interval = cli
+            .sample_interval_ms
+            .or(file.devices.sample_interval_ms)
+            .unwrap_or(DEFAULT_SAMPLE_INTERVAL_MS);
+        check(sample_interval > 0, "sample interval must be positive");
+        let max_clock_skew = Duration::from_millis(
+            cli.max_clock_skew_ms
+                .or(file.devices.max_clock_skew_ms)
+                .unwrap_or(DEFAULT_MAX_CLOCK_SKEW_MS),
+        );
+        let location_allowlist = cli
+            .location_allowlist
+            .or(file.devices.location_allowlist)
+            .map(|names| {
+                names
+                    .iter()
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            });
+
+        let journal_mode = match cli
+            .journal_mode
+            .or(file.database.journal_mode)
+            .map(|v| v.to_lowercase())
+            .as_deref()
+        {
+            None | Some("wal") => JournalMode::Wal,
+            Some("delete") => JournalMode::Delete,
+            Some("truncate") => JournalMode::Truncate,
+            Some("persist") => JournalMode::Persist,
+            Some(other) => {
+                check(
+                    false,
+                    &format!(
+                        "journal mode must be wal, delete, truncate or persist, got {}",
+                        other
+                    ),
+                );
+                JournalMode::Wal
+            }
+        };
+        let synchronous = match cli
+            .synchronous
+            .or(file.database.synchronous)
+            .map(|v| v.to_lowercase())
+            .as_deref()
+        {
+            None | Some("normal") => "NORMAL",
+            Some("full") => "FULL",
+            Some("extra") => "EXTRA",
+            Some("off") => "OFF",
+            Some(other) => {
+                check(
+                    false,
+                    &format!(
+                        "synchronous must be off, normal, full or extra, got {}",
+                        other
+                    ),
+                );
+                "NORMAL"
+            }
+        };
+        let path = cli.db_path.or(file.database.path);
+        let db = DbSettings {
+            // only the default moved, a configured path is used as it is
+            legacy_path: path.is_none().then(|| db::LEGACY_DB_PATH.to_string()),
+            path: path.unwrap_or_else(|| DEFAULT_DB_PATH.to_string()),
+            journal_mode,
+            synchronous,
+            busy_timeout: Duration::from_millis(
+                cli.busy_timeout_ms
+                    .or(file.database.busy_timeout_ms)
+                    .unwrap_or(DEFAULT_DB_BUSY_TIMEOUT_MS),
+            ),
+            checkpoint_interval: Duration::from_secs(
+                cli.checkpoint_interval_secs
+                    .or(file.database.checkpoint_interval_secs)
+                    .unwrap_or(DEFAULT_DB_CHECKPOINT_INTERVAL_SECS),
+            ),
+            pool_size: cli
+                .pool_size
+                .or(file.database.pool_size)
+                .unwrap_or(DEFAULT_DB_POOL_SIZE),
+            read_connections: cli
+                .read_connections
+                .or(file.database.read_connections)
+                .unwrap_or(DEFAULT_DB_READ_CONNECTIONS),
+        };
+        check(db.pool_size > 0, "database pool size must be positive");
+        check(
+            db.read_connections > 0,
+            "database read connections must be positive",
+        );
+
+        let batch = BatchSettings {
+            queue_size: cli
+                .ingest_queue_size
+                .or(file.ingest.queue_size)
+                .unwrap_or(DEFAULT_INGEST_QUEUE_SIZE),
+            max_batch: cli
+                .ingest_batch_size
+                .or(file.ingest.batch_size)
+                .unwrap_or(DEFAULT_INGEST_BATCH_SIZE),
+            max_delay: Duration::from_millis(
+                cli.ingest_batch_window_ms
+                    .or(file.ingest.batch_window_ms)
+                    .unwrap_or(DEFAULT_INGEST_BATCH_WINDOW_MS),
+            ),
+        };
+        check(batch.queue_size > 0, "ingest queue size must be positive");
+        check(batch.max_batch > 0, "ingest batch size must be positive");
+
+        let retention_enabled = cli
+            .retention_enabled
+            .or(file.retention.enabled)
+            .unwrap_or(true);
+        let retention = RetentionSettings {
+            interval: match retention_enabled {
+                true => Duration::from_secs(
+                    cli.retention_interval_secs
+                        .or(file.retention.interval_secs)
+                        .unwrap_or(DEFAULT_RETENTION_INTERVAL_SECS),
+                ),
+                false => Duration::ZERO,
+            },
+            // device timestamps can be up to max_clock_skew behind, leave a minute on top
+            settle: max_clock_skew + Duration::from_secs(60),
+            policies: file.retention.policies,
+        };
+        for (index, policy) in retention.policies.iter().enumerate() {
+            check(
+                [policy.raw_days, policy.hourly_days, policy.daily_days]
+                    .iter()
+                    .all(|days| *days != Some(0)),
+                "retention days must be positive",
+            );
+            check(
+                retention.policies[..index].iter().all(|earlier| {
+                    (&earlier.location, &earlier.measurand) != (&policy.location, &policy.measurand)
+                }),
+                &format!(
+                    "more than one retention policy for location {} and measurand {}",
+                    policy.location.as_deref().unwrap_or("*"),
+                    policy.measurand.as_deref().unwrap_or("*")
+                ),
+            );
+        }
+
+        let backup_enabled = cli.backup_enabled.or(file.backup.enabled).unwrap_or(true);
+        let backup = BackupSettings {
+            dir: cli
+                .backup_dir
+                .or(file.backup.dir)
+                .unwrap_or_else(|| DEFAULT_BACKUP_DIR.into()),
+            interval: match backup_enabled {
+                true => Duration::from_secs(
+                    cli.backup_interval_secs
+                        .or(file.backup.interval_secs)
+                        .unwrap_or(DEFAULT_BACKUP_INTERVAL_SECS),
+                ),
+                false => Duration::ZERO,
+            },
+            keep: cli
+                .backup_keep
+                .or(file.backup.keep)
+                .unwrap_or(DEFAULT_BACKUP_KEEP),
+        };
+        check(backup.keep > 0, "backups to keep must be positive");
+
+        for sink in &file.notifications.sinks {
+            match sink {
+                SinkSettings::Webhook { url }
+                | SinkSettings::Ntfy { url, .. }
+                | SinkSettings::Gotify { url, .. } => check(
+                    reqwest::Url::parse(url).is_ok(),
+                    &format!("notification url {} is not a url", url),
+                ),
+                SinkSettings::Email { from, to, .. } => {
+                    check(!to.is_empty(), "email notifications need a recipient");
+                    for address in std::iter::once(from).chain(to) {
+                        check(
+                            address.parse::<lettre::message::Mailbox>().is_ok(),
+                            &format!("notification address {} is not an email address", address),
+                        );
+                    }
+                }
+                SinkSettings::Command { .. } => {}
+            }
+        }
+        let notifications = NotifySettings {
+            sinks: file.notifications.sinks,
+            title: file
+                .notifications
+                .title
+                .unwrap_or_else(|| DEFAULT_NOTIFY_TITLE.to_string()),
+            message: file
+                .notifications
+                .message
+                .unwrap_or_else(|| DEFAULT_NOTIFY_MESSAGE.to_string()),
+            retries: cli
+                .notify_retries
+                .or(file.notifications.retries)
+                .unwrap_or(DEFAULT_NOTIFY_RETRIES),
+            backoff: Duration::from_millis(
+                cli.notify_retry_backoff_ms
+                    .or(file.notifications.retry_backoff_ms)
+                    .unwrap_or(DEFAULT_NOTIFY_RETRY_BACKOFF_MS),
+            ),
+            dry_run: cli
+                .notify_dry_run
+                .or(file.notifications.dry_run)
+                .unwrap_or(false),
+        };
+
+        if !errors.is_empty() {
+            return Err(SettingsError::Invalid(errors));
+        }
+
+        Ok(Self {
+            udp_addr: SocketAddr::new(bind, udp_port.unwrap_or_default()),
+            tcp_addr: SocketAddr::new(bind, tcp_port.unwrap_or_default()),
+            http_addr: cli
+                .http_port
+                .or(file.network.http_port)
+                .map(|port| SocketAddr::new(bind, port)),
+            buffer_size,
+            log_level: cli.log_level.or(file.log_level).unwrap_or(LogLevel::Info),
+            log_format: cli
+                .log_format
+                .or(file.log_format)
+                .unwrap_or(LogFormat::Text),
+            shutdown_timeout: Duration::from_secs(
+                cli.shutdown_timeout_secs
+                    .or(file.shutdown_timeout_secs)
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            ),
+            sample_interval,
+            auto_create_locations: cli
+                .auto_create_locations
+                .or(file.devices.auto_create_locations)
+                .unwrap_or(true),
+            location_allowlist,
+            max_clock_skew,
+            batch,
+            quarantine_dir: cli.quarantine_dir.or(file.ingest.quarantine_dir),
+            series_metrics: cli.series_metrics.or(file.metrics.series).unwrap_or(true),
+            db,
+            retention,
+            backup,
+            notifications,
+        })
+    }
+}
+
+fn read_file(path: &std::path::Path) -> Result<FileConfig, SettingsError> {
+    let text = std::fs::read_to_string(path).map_err(|source| SettingsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    toml::from_str(&text).map_err(|source| SettingsError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(text: &str) -> FileConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let cli = Cli {
+            udp_port: Some(9000),
+            ..Default::default()
+        };
+        let config = Config::merge(
+            cli,
+            file("[network]\nbind = \"::\"\nudp_port = 1\ntcp_port = 2\n"),
+        )
+        .unwrap();
+        assert_eq!("[::]:9000", config.udp_addr.to_string());
+        assert_eq!("[::]:2", config.tcp_addr.to_string());
+        assert_eq!(BUFFER_SIZE, config.buffer_size);
+    }
+
+    #[test]
+    fn test_reports_every_invalid_setting() {
+        let cli = Cli {
+            buffer_size: Some(16),
+            ..Default::default()
+        };
+        let config = Config::merge(cli, file("[database]\njournal_mode = \"fast\"\n"));
+        let Err(SettingsError::Invalid(errors)) = config else {
+            panic!("expected validation errors");
+        };
+        // both ports, the buffer and the journal mode
+        assert_eq!(4, errors.len());
+    }
+
+    #[test]
+    fn test_notification_sinks_are_checked() {
+        let cli = Cli {
+            udp_port: Some(1),
+            tcp_port: Some(2),
+            ..Default::default()
+        };
+        let config = Config::merge(
+            cli,
+            file(
+                "[[notifications.sinks]]\n\
+                kind = \"ntfy\"\n\
+                url = \"ntfy.sh/freezer\"\n\
+                [[notifications.sinks]]\n\
+                kind = \"email\"\n\
+                host = \"localhost\"\n\
+                from = \"sensors@localhost\"\n\
+                to = []\n\
+                [[notifications.sinks]]\n\
+                kind = \"email\"\n\
+                host = \"localhost\"\n\
+                from = \"sensors\"\n\
+                to = [\"Ops <ops@localhost>\", \"ops at localhost\"]\n",
+            ),
+        );
+        let Err(SettingsError::Invalid(errors)) = config else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(4, errors.len());
+        assert!(
+            toml::from_str::<FileConfig>("[[notifications.sinks]]\nkind = \"pager\"\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_retention_policies_are_checked() {
+        let cli = || Cli {
+            udp_port: Some(1),
+            tcp_port: Some(2),
+            ..Default::default()
+        };
+        let policies = "[[retention.policies]]\n\
+            raw_days = 30\n\
+            [[retention.policies]]\n\
+            measurand = \"humidity\"\n\
+            raw_days = 7\n\
+            hourly_days = 90\n";
+        let config = Config::merge(cli(), file(policies)).unwrap();
+        assert_eq!(2, config.retention.policies.len());
+        assert_eq!(
+            Some("humidity"),
+            config.retention.policies[1].measurand.as_deref()
+        );
+
+        let config = Config::merge(
+            cli(),
+            file(&format!(
+                "{}[[retention.policies]]\nraw_days = 0\n",
+                policies
+            )),
+        );
+        let Err(SettingsError::Invalid(errors)) = config else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(2, errors.len());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<FileConfig>("[network]\nudp = 1\n").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Grafana provisioning generated from the registered devices.
+//!
+//! `server dashboards <dir>` writes a datasource pointing at the server's Grafana
+//! endpoint (see [`crate::grafana`]) and a dashboard with a row per location and a panel
+//! per measurand:
+//!
+//! ```text
+//! <dir>/datasources/sensors.yaml
+//! <dir>/dashboards/sensors.yaml
+//! <dir>/dashboards/sensors.json
+//! ```
+//!
+//! Mount `<dir>` at `/etc/grafana/provisioning` and run the command again whenever a
+//! device is added, Grafana picks up the changed dashboard by itself.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use async_sqlite::rusqlite::{self, Connection};
+use serde_json::{Value, json};
+use thiserror::Error;
+
+use crate::api;
+
+/// The uid provisioned panels refer to the datasource by.
+const DATASOURCE_UID: &str = "sensors";
+const DATASOURCE_TYPE: &str = "simpod-json-datasource";
+/// Where Grafana sees `<dir>/dashboards`.
+const DASHBOARDS_PATH: &str = "/etc/grafana/provisioning/dashboards";
+const PANEL_WIDTH: i64 = 12;
+const PANEL_HEIGHT: i64 = 8;
+
+#[derive(Error, Debug)]
+pub(crate) enum DashboardError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to encode dashboard: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Units of each measurand, by location.
+type Measurands = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Writes the provisioning for every registered device into `dir`, returns the number
+/// of panels.
+pub(crate) fn generate(
+    conn: &Connection,
+    dir: &Path,
+    datasource_url: &str,
+) -> Result<usize, DashboardError> {
+    let measurands = measurands(conn)?;
+
+    fs::create_dir_all(dir.join("datasources"))?;
+    fs::create_dir_all(dir.join("dashboards"))?;
+    fs::write(
+        dir.join("datasources/sensors.yaml"),
+        datasource(datasource_url)?,
+    )?;
+    fs::write(dir.join("dashboards/sensors.yaml"), provider()?)?;
+    fs::write(
+        dir.join("dashboards/sensors.json"),
+        serde_json::to_string_pretty(&dashboard(&measurands))?,
+    )?;
+
+    Ok(measurands.values().map(BTreeMap::len).sum())
+}
+
+/// The measurands registered devices report. When devices disagree about a unit the
+/// first one to register wins.
+fn measurands(conn: &Connection) -> rusqlite::Result<Measurands> {
+    let mut measurands = Measurands::new();
+    for device in api::devices(conn)? {
+        let location = measurands.entry(device.location).or_default();
+        for (measurand, unit) in device.measurands.into_iter().zip(device.units) {
+            if !measurand.is_empty() {
+                location.entry(measurand).or_insert(unit);
+            }
+        }
+    }
+    Ok(measurands)
+}
+
+// YAML is a superset of JSON, quoting through serde_json escapes whatever the url holds
+fn datasource(url: &str) -> serde_json::Result<String> {
+    Ok(format!(
+        "apiVersion: 1\n\
+        datasources:\n  \
+          - name: Sensors\n    \
+            type: {DATASOURCE_TYPE}\n    \
+            uid: {DATASOURCE_UID}\n    \
+            access: proxy\n    \
+            url: {}\n    \
+            isDefault: true\n    \
+            editable: false\n",
+        serde_json::to_string(url)?
+    ))
+}
+
+fn provider() -> serde_json::Result<String> {
+    Ok(format!(
+        "apiVersion: 1\n\
+        providers:\n  \
+          - name: sensors\n    \
+            type: file\n    \
+            disableDeletion: true\n    \
+            updateIntervalSeconds: 30\n    \
+            options:\n      \
+              path: {}\n",
+        serde_json::to_string(DASHBOARDS_PATH)?
+    ))
+}
+
+fn dashboard(measurands: &Measurands) -> Value {
+    let datasource = json!({ "type": DATASOURCE_TYPE, "uid": DATASOURCE_UID });
+    let mut panels = vec![];
+    let mut y = 0;
+    for (location, units) in measurands {
+        panels.push(json!({
+            "type": "row",
+            "id": panels.len() + 1,
+            "title": location,
+            "collapsed": false,
+            "gridPos": { "h": 1, "w": 24, "x": 0, "y": y },
+            "panels": [],
+        }));
+        y += 1;
+        for (i, (measurand, unit)) in units.iter().enumerate() {
+            let column = i as i64 % 2;
+            panels.push(json!({
+                "type": "timeseries",
+                "id": panels.len() + 1,
+                "title": measurand,
+                "datasource": datasource,
+                "targets": [{
+                    "refId": "A",
+                    "datasource": datasource,
+                    "target": format!("{location}/{measurand}"),
+                }],
+                "fieldConfig": {
+                    "defaults": { "unit": grafana_unit(unit) },
+                    "overrides": [],
+                },
+                "gridPos": {
+                    "h": PANEL_HEIGHT,
+                    "w": PANEL_WIDTH,
+                    "x": column * PANEL_WIDTH,
+                    "y": y + i as i64 / 2 * PANEL_HEIGHT,
+                },
+            }));
+        }
+        y += (units.len() as i64 + 1) / 2 * PANEL_HEIGHT;
+    }
+
+    json!({
+        "uid": "sensors",
+        "title": "Sensors",
+        "tags": ["sensors"],
+        "timezone": "browser",
+        "refresh": "1m",
+        "time": { "from": "now-24h", "to": "now" },
+        "schemaVersion": 39,
+        "panels": panels,
+    })
+}
+
+/// Grafana's id for a unit devices register with, a plain suffix for anything unknown.
+fn grafana_unit(unit: &str) -> String {
+    let id = match unit.trim() {
+        "" => "none",
+        "C" | "°C" | "degC" => "celsius",
+        "F" | "°F" | "degF" => "fahrenheit",
+        "K" => "kelvin",
+        "%" => "percent",
+        "%RH" | "RH" => "humidity",
+        "Pa" => "pressurepa",
+        "hPa" => "pressurehpa",
+        "kPa" => "pressurekpa",
+        "lx" | "lux" => "lux",
+        "ppm" => "ppm",
+        "V" => "volt",
+        "A" => "amp",
+        "W" => "watt",
+        "dB" => "dB",
+        other => return format!("suffix:{other}"),
+    };
+    id.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rows_per_location_and_panels_per_measurand() {
+        let mut measurands = Measurands::new();
+        measurands.insert(
+            "basement".to_string(),
+            BTreeMap::from([
+                ("humidity".to_string(), "%".to_string()),
+                ("pressure".to_string(), "hPa".to_string()),
+                ("temperature".to_string(), "C".to_string()),
+            ]),
+        );
+        measurands.insert(
+            "freezer".to_string(),
+            BTreeMap::from([("temperature".to_string(), "C".to_string())]),
+        );
+
+        let dashboard = dashboard(&measurands);
+        let panels = dashboard["panels"].as_array().unwrap();
+        let titles = panels
+            .iter()
+            .map(|panel| panel["title"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "basement",
+                "humidity",
+                "pressure",
+                "temperature",
+                "freezer",
+                "temperature"
+            ],
+            titles
+        );
+        assert_eq!("row", panels[4]["type"]);
+        // two rows of basement panels are above the freezer row
+        assert_eq!(1 + 2 * PANEL_HEIGHT, panels[4]["gridPos"]["y"]);
+        assert_eq!("freezer/temperature", panels[5]["targets"][0]["target"]);
+        assert_eq!("pressurehpa", panels[2]["fieldConfig"]["defaults"]["unit"]);
+    }
+
+    #[test]
+    fn test_unknown_units_become_suffixes() {
+        assert_eq!("celsius", grafana_unit("C"));
+        assert_eq!("none", grafana_unit(""));
+        assert_eq!("suffix:µg/m³", grafana_unit("µg/m³"));
+    }
+}
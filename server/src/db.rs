@@ -0,0 +1,449 @@
+//! Opening the database and bringing its schema up to date.
+//!
+//! Migrations live in `server/migrations`, are embedded into the binary and applied in
+//! order. The number of applied migrations is tracked in `PRAGMA user_version`, so a
+//! fresh file gets the whole schema and an existing one only what it is missing.
+//!
+//! Ingest writes through one pool while queries get a second, read-only pool. In WAL
+//! mode readers (including Grafana opening the same file) never wait for the writer.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use async_sqlite::{
+    JournalMode, Pool, PoolBuilder,
+    rusqlite::{self, Connection, OpenFlags},
+};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Every migration in the order it is applied. Never edit or reorder an entry once it
+/// has shipped, add a new one instead.
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("initial", include_str!("../migrations/0001_initial.sql")),
+    (
+        "unique location name",
+        include_str!("../migrations/0002_unique_location_name.sql"),
+    ),
+    (
+        "series and samples",
+        include_str!("../migrations/0003_series_sample.sql"),
+    ),
+    (
+        "millisecond timestamps",
+        include_str!("../migrations/0004_millisecond_timestamps.sql"),
+    ),
+    (
+        "retention and rollups",
+        include_str!("../migrations/0005_retention_rollups.sql"),
+    ),
+    ("alerts", include_str!("../migrations/0006_alerts.sql")),
+    (
+        "event delivery",
+        include_str!("../migrations/0007_event_delivery.sql"),
+    ),
+    (
+        "reading delivery",
+        include_str!("../migrations/0008_reading_delivery.sql"),
+    ),
+    (
+        "retention policy config",
+        include_str!("../migrations/0009_retention_policy_config.sql"),
+    ),
+    (
+        "log delivery",
+        include_str!("../migrations/0010_log_delivery.sql"),
+    ),
+];
+
+/// Where earlier versions kept the database, relative to the server directory.
+pub(crate) const LEGACY_DB_PATH: &str = "db.sqlite3";
+
+#[derive(Error, Debug)]
+pub(crate) enum DbError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] async_sqlite::Error),
+    #[error("Unable to create or move the database files: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(
+        "Found a database at {legacy} and at {path}, remove or move away one of them before starting"
+    )]
+    Conflict { legacy: String, path: String },
+    #[error("Database schema version {found} is newer than this server supports ({supported})")]
+    TooNew { found: usize, supported: usize },
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value.into())
+    }
+}
+
+/// How connections to the database file are set up.
+#[derive(Debug, Clone)]
+pub(crate) struct DbSettings {
+    pub(crate) path: String,
+    /// A database left where earlier versions kept it, see [`move_legacy`].
+    pub(crate) legacy_path: Option<String>,
+    pub(crate) journal_mode: JournalMode,
+    /// Value for `PRAGMA synchronous`, `NORMAL` is safe in WAL mode.
+    pub(crate) synchronous: &'static str,
+    /// How long a connection waits on a lock before giving up.
+    pub(crate) busy_timeout: Duration,
+    /// How often the WAL is written back into the database, zero disables it.
+    pub(crate) checkpoint_interval: Duration,
+    /// Connections used for writing.
+    pub(crate) pool_size: usize,
+    pub(crate) read_connections: usize,
+}
+
+/// Moves the database at `settings.legacy_path`, with its WAL and shared memory files,
+/// to `settings.path`. Refuses when both exist instead of picking one.
+pub(crate) fn move_legacy(settings: &DbSettings) -> Result<(), DbError> {
+    let Some(legacy) = settings
+        .legacy_path
+        .as_ref()
+        .filter(|legacy| Path::new(legacy).exists())
+    else {
+        return Ok(());
+    };
+    if Path::new(&settings.path).exists() {
+        return Err(DbError::Conflict {
+            legacy: legacy.clone(),
+            path: settings.path.clone(),
+        });
+    }
+    create_parent(&settings.path)?;
+    // the database itself last, an interrupted move is finished on the next start
+    for suffix in ["-wal", "-shm", ""] {
+        let from = format!("{}{}", legacy, suffix);
+        if Path::new(&from).exists() {
+            std::fs::rename(&from, format!("{}{}", settings.path, suffix))?;
+        }
+    }
+    info!(from = legacy, to = settings.path, "moved database");
+    Ok(())
+}
+
+/// Creates the directory `path` lives in, a fresh checkout has no data directory yet.
+fn create_parent(path: &str) -> std::io::Result<()> {
+    match Path::new(path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        Some(dir) => std::fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+}
+
+/// Opens the pool at `settings.path` and applies any outstanding migrations.
+pub async fn open(settings: &DbSettings) -> Result<Pool, DbError> {
+    create_parent(&settings.path)?;
+    let pool = PoolBuilder::new()
+        .path(&settings.path)
+        .num_conns(settings.pool_size)
+        .open()
+        .await?;
+
+    // the journal mode is stored in the file, switch it once before the pool is used
+    let journal_mode = settings.journal_mode.as_str();
+    let mode: String = pool
+        .conn(move |conn| {
+            conn.pragma_update_and_check(None, "journal_mode", journal_mode, |row| row.get(0))
+        })
+        .await?;
+    if !mode.eq_ignore_ascii_case(journal_mode) {
+        warn!(requested = journal_mode, mode, "unable to set journal mode");
+    }
+    configure(&pool, settings).await?;
+
+    let applied = pool.conn_mut(|conn| Ok(migrate(conn))).await??;
+    if applied > 0 {
+        info!(migrations = applied, "migrated database");
+    }
+    Ok(pool)
+}
+
+/// Opens a pool that can only read the database, for serving queries. Open it after
+/// [`open`] so the file and its schema exist.
+pub async fn open_read_only(settings: &DbSettings) -> Result<Pool, DbError> {
+    let pool = PoolBuilder::new()
+        .path(&settings.path)
+        .flags(
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI,
+        )
+        .num_conns(settings.read_connections)
+        .open()
+        .await?;
+    configure(&pool, settings).await?;
+    Ok(pool)
+}
+
+/// Applies the per connection pragmas to every connection of `pool`.
+async fn configure(pool: &Pool, settings: &DbSettings) -> Result<(), DbError> {
+    let synchronous = settings.synchronous;
+    let busy_timeout = settings.busy_timeout;
+    pool.conn_for_each(move |conn| {
+        conn.busy_timeout(busy_timeout)?;
+        conn.pragma_update(None, "synchronous", synchronous)
+    })
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    Ok(())
+}
+
+/// Periodically moves the WAL back into the database file so it doesn't grow while
+/// readers keep old snapshots alive. Returns `None` if there is nothing to checkpoint.
+pub(crate) fn spawn_checkpoints(pool: Arc<Pool>, settings: &DbSettings) -> Option<JoinHandle<()>> {
+    if !matches!(settings.journal_mode, JournalMode::Wal) || settings.checkpoint_interval.is_zero()
+    {
+        return None;
+    }
+
+    let mut interval = tokio::time::interval(settings.checkpoint_interval);
+    Some(tokio::spawn(async move {
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = checkpoint(&pool).await {
+                error!(error = %e, "unable to checkpoint database");
+            }
+        }
+    }))
+}
+
+/// Moves the WAL back into the database file and truncates it. Outside of WAL mode there
+/// is nothing to do and this returns right away.
+pub(crate) async fn checkpoint(pool: &Pool) -> Result<(), async_sqlite::Error> {
+    let (busy, frames) = pool
+        .conn(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+            })
+        })
+        .await?;
+    if busy != 0 {
+        debug!(frames, "checkpoint blocked by readers");
+    }
+    Ok(())
+}
+
+/// The schema version a fully migrated database has.
+pub(crate) fn schema_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Applies outstanding migrations, each in its own transaction. Returns how many ran.
+pub(crate) fn migrate(conn: &mut Connection) -> Result<usize, DbError> {
+    let current: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if current > MIGRATIONS.len() {
+        return Err(DbError::TooNew {
+            found: current,
+            supported: MIGRATIONS.len(),
+        });
+    }
+
+    for (index, (name, sql)) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = index + 1;
+        info!(version, name, "migrating database");
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+
+    Ok(MIGRATIONS.len() - current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(path: &str) -> DbSettings {
+        DbSettings {
+            path: path.to_string(),
+            legacy_path: None,
+            journal_mode: JournalMode::Wal,
+            synchronous: "NORMAL",
+            busy_timeout: Duration::from_secs(5),
+            checkpoint_interval: Duration::ZERO,
+            pool_size: 2,
+            read_connections: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_pool_sees_writes_but_cannot_write() {
+        let dir = std::env::temp_dir().join(format!("server-db-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite3");
+        let path = path.to_str().unwrap();
+
+        let pool = open(&settings(path)).await.unwrap();
+        let read_pool = open_read_only(&settings(path)).await.unwrap();
+
+        let mode: String = read_pool
+            .conn(|conn| conn.pragma_query_value(None, "journal_mode", |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!("wal", mode);
+
+        pool.conn(|conn| conn.execute("INSERT INTO location (name) VALUES ('hall')", []))
+            .await
+            .unwrap();
+        let locations: usize = read_pool
+            .conn(|conn| conn.query_row("SELECT COUNT(*) FROM location", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(2, locations);
+        assert!(
+            read_pool
+                .conn(|conn| conn.execute("INSERT INTO location (name) VALUES ('attic')", []))
+                .await
+                .is_err()
+        );
+
+        pool.close().await.unwrap();
+        read_pool.close().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_database_is_moved_once() {
+        let dir = std::env::temp_dir().join(format!("server-legacy-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let legacy = dir.join("db.sqlite3");
+        let path = dir.join("data").join("db.sqlite3");
+        std::fs::write(&legacy, "database").unwrap();
+        std::fs::write(dir.join("db.sqlite3-wal"), "wal").unwrap();
+        let mut settings = settings(path.to_str().unwrap());
+        settings.legacy_path = Some(legacy.to_str().unwrap().to_string());
+
+        move_legacy(&settings).unwrap();
+        assert!(!legacy.exists());
+        assert_eq!("database", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "wal",
+            std::fs::read_to_string(dir.join("data").join("db.sqlite3-wal")).unwrap()
+        );
+        move_legacy(&settings).unwrap();
+
+        std::fs::write(&legacy, "other").unwrap();
+        assert!(matches!(
+            move_legacy(&settings),
+            Err(DbError::Conflict { .. })
+        ));
+        assert_eq!("database", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(MIGRATIONS.len(), migrate(&mut conn).unwrap());
+        assert_eq!(0, migrate(&mut conn).unwrap());
+
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len(), version);
+    }
+
+    #[test]
+    fn test_migrate_adopts_hand_made_schema() {
+        // databases created from schema.sql before migrations existed have version 0
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let kitchens: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM location WHERE name = 'kitchen'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(1, kitchens);
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(migrate(&mut conn), Err(DbError::TooNew { .. })));
+    }
+
+    #[test]
+    fn test_duplicate_locations_are_merged() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        conn.execute_batch(
+            "INSERT INTO location (name) VALUES ('kitchen');
+            INSERT INTO data (location_id, value) VALUES (2, 1.0);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let location_ids: Vec<u32> = conn
+            .prepare("SELECT DISTINCT location_id FROM data")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(vec![1], location_ids);
+        assert!(
+            conn.execute("INSERT INTO location (name) VALUES ('kitchen')", [])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_readings_are_moved_into_series() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        conn.execute_batch(
+            "INSERT INTO data (location_id, timestamp, value, measurand, units) VALUES
+                (1, 10, 20.5, 'temperature', 'C'),
+                (1, 12, 21.0, 'temperature', 'C'),
+                (1, 12, 40.0, 'humidity', NULL);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let series: usize = conn
+            .query_row("SELECT COUNT(*) FROM series", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(2, series);
+
+        let rows: Vec<(u32, i64, f64, String, String)> = conn
+            .prepare("SELECT id, timestamp, value, measurand, units FROM data ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                (1, 10, 20.5, "temperature".to_string(), "C".to_string()),
+                (2, 12, 21.0, "temperature".to_string(), "C".to_string()),
+                (3, 12, 40.0, "humidity".to_string(), "".to_string()),
+            ],
+            rows
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! The Grafana JSON datasource protocol, so dashboards can query the server instead of
+//! opening the database file.
+//!
+//! Add a JSON datasource with the URL `http://<server>:<http port>/grafana`. Targets are
+//! written `location/measurand`, optionally followed by `/device`, and either part may
+//! be `*` to match everything, e.g. `*/temperature`. Readings are aggregated on the
+//! server into buckets of Grafana's interval, the mean unless the target's payload says
+//! `{"aggregate": "min"}` or `"max"`. Events are served as annotations, the annotation
+//! query selects a location.
+
+use std::collections::BTreeSet;
+
+use async_sqlite::rusqlite::{self, Connection, params};
+use axum::{
+    Json, Router,
+    extract::State,
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, ApiError, SeriesFilter},
+    http::HttpState,
+    retention::HOUR_MS,
+};
+
+const DEFAULT_MAX_POINTS: i64 = 1000;
+const MAX_ANNOTATIONS: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone)]
+struct TimeRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QueryRequest {
+    range: TimeRange,
+    interval_ms: Option<i64>,
+    max_data_points: Option<i64>,
+    targets: Vec<Target>,
+    #[serde(default)]
+    adhoc_filters: Vec<AdhocFilter>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Target {
+    #[serde(default)]
+    target: String,
+    #[serde(default)]
+    data: Option<TargetData>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct TargetData {
+    aggregate: Option<Aggregate>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Aggregate {
+    #[default]
+    Mean,
+    Min,
+    Max,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct AdhocFilter {
+    key: String,
+    operator: String,
+    value: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct TimeSeries {
+    target: String,
+    /// `[value, milliseconds]` pairs, the order Grafana expects.
+    datapoints: Vec<(f64, i64)>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct SearchRequest {
+    target: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnnotationRequest {
+    range: TimeRange,
+    annotation: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+struct Annotation {
+    /// Echoed back, Grafana matches annotations to their query with it.
+    annotation: serde_json::Value,
+    time: i64,
+    title: String,
+    text: String,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct TagKey {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: &'static str,
+}
+
+#[derive(Deserialize, Debug)]
+struct TagValuesRequest {
+    key: String,
+}
+
+#[derive(Serialize, Debug)]
+struct TagValue {
+    text: String,
+}
+
+pub(crate) fn router() -> Router<HttpState> {
+    Router::new()
+        .route("/grafana", get(health))
+        .route("/grafana/", get(health))
+        .route("/grafana/search", post(search))
+        .route("/grafana/query", post(query))
+        .route("/grafana/annotations", post(annotations))
+        .route("/grafana/tag-keys", post(tag_keys))
+        .route("/grafana/tag-values", post(tag_values))
+}
+
+/// Grafana's "Save & test" only checks that this answers.
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn search(
+    State(state): State<HttpState>,
+    request: Option<Json<SearchRequest>>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let needle = request.map(|Json(r)| r.target).unwrap_or_default();
+    let targets = state
+        .read_pool
+        .conn(move |conn| {
+            let targets = api::series(conn, &SeriesFilter::default())?
+                .into_iter()
+                .map(|series| format!("{}/{}", series.location, series.measurand))
+                .filter(|target| target.contains(needle.trim()))
+                .collect::<BTreeSet<_>>();
+            Ok(targets.into_iter().collect())
+        })
+        .await?;
+    Ok(Json(targets))
+}
+
+async fn query(
+    State(state): State<HttpState>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<Vec<TimeSeries>>, ApiError> {
+    for filter in &request.adhoc_filters {
+        if filter.operator != "=" {
+            return Err(ApiError::BadRequest(format!(
+                "unsupported filter operator {}, only = is",
+                filter.operator
+            )));
+        }
+    }
+    let (from, to) = range(&state, request.range).await?;
+    let max_points = request
+        .max_data_points
+        .filter(|points| *points > 0)
+        .unwrap_or(DEFAULT_MAX_POINTS)
+        .min(api::MAX_LIMIT as i64);
+    let width = bucket_width(from, to, request.interval_ms, max_points);
+    let results = state
+        .read_pool
+        .conn(move |conn| {
+            let mut results = vec![];
+            for target in &request.targets {
+                let Some(filter) = parse_target(&target.target) else {
+                    continue;
+                };
+                let aggregate = target
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.aggregate)
+                    .unwrap_or_default();
+                let matched = api::series(conn, &filter)?
+                    .into_iter()
+                    .filter(|series| adhoc_matches(&request.adhoc_filters, series))
+                    .collect::<Vec<_>>();
+                let several = matched.len() > 1;
+                for series in matched {
+                    let buckets =
+                        api::buckets(conn, series.id, from, to, width, max_points as usize)?;
+                    let datapoints = buckets
+                        .into_iter()
+                        .map(|bucket| {
+                            let value = match aggregate {
+                                Aggregate::Mean => bucket.mean,
+                                Aggregate::Min => bucket.min,
+                                Aggregate::Max => bucket.max,
+                            };
+                            (value, bucket.timestamp)
+                        })
+                        .collect();
+                    let name = match several {
+                        true => {
+                            format!("{}/{}/{}", series.location, series.measurand, series.device)
+                        }
+                        false => format!("{}/{}", series.location, series.measurand),
+                    };
+                    results.push(TimeSeries {
+                        target: name,
+                        datapoints,
+                    });
+                }
+            }
+            Ok(results)
+        })
+        .await?;
+    Ok(Json(results))
+}
+
+async fn annotations(
+    State(state): State<HttpState>,
+    Json(request): Json<AnnotationRequest>,
+) -> Result<Json<Vec<Annotation>>, ApiError> {
+    let location = request
+        .annotation
+        .get("query")
+        .and_then(|query| query.as_str())
+        .map(|query| query.trim().to_lowercase())
+        .filter(|query| !query.is_empty() && query != "*");
+    let (from, to) = range(&state, request.range.clone()).await?;
+    let annotations = state
+        .read_pool
+        .conn(move |conn| {
+            let events = events(conn, from, to, location.as_deref())?;
+            Ok(events
+                .into_iter()
+                .map(|(time, location, kind, channel)| Annotation {
+                    annotation: request.annotation.clone(),
+                    time,
+                    title: kind.clone(),
+                    text: format!("{} on channel {} in {}", kind, channel, location),
+                    tags: vec![location, kind],
+                })
+                .collect())
+        })
+        .await?;
+    Ok(Json(annotations))
+}
+
+async fn tag_keys() -> Json<Vec<TagKey>> {
+    Json(vec![
+        TagKey {
+            kind: "string",
+            text: "location",
+        },
+        TagKey {
+            kind: "string",
+            text: "measurand",
+        },
+        TagKey {
+            kind: "number",
+            text: "device",
+        },
+    ])
+}
+
+async fn tag_values(
+    State(state): State<HttpState>,
+    Json(request): Json<TagValuesRequest>,
+) -> Result<Json<Vec<TagValue>>, ApiError> {
+    let values = state
+        .read_pool
+        .conn(move |conn| {
+            let series = api::series(conn, &SeriesFilter::default())?;
+            let values = series
+                .into_iter()
+                .filter_map(|series| match request.key.as_str() {
+                    "location" => Some(series.location),
+                    "measurand" => Some(series.measurand),
+                    "device" => Some(series.device.to_string()),
+                    _ => None,
+                })
+                .collect::<BTreeSet<_>>();
+            Ok(values.into_iter().map(|text| TagValue { text }).collect())
+        })
+        .await?;
+    Ok(Json(values))
+}
+
+/// Turns Grafana's ISO 8601 range into milliseconds.
+async fn range(state: &HttpState, range: TimeRange) -> Result<(i64, i64), ApiError> {
+    let parsed = state
+        .read_pool
+        .conn(move |conn| parse_range(conn, &range))
+        .await?;
+    match parsed {
+        Some((from, to)) if from < to => Ok((from, to)),
+        Some(_) => Err(ApiError::BadRequest("from must be before to".to_string())),
+        None => Err(ApiError::BadRequest("range is not ISO 8601".to_string())),
+    }
+}
+
+/// Lets SQLite parse the range, `None` if either end isn't a time.
+fn parse_range(conn: &Connection, range: &TimeRange) -> rusqlite::Result<Option<(i64, i64)>> {
+    conn.query_row(
+        "SELECT
+            CAST(unixepoch(?1, 'subsec') * 1000 AS INTEGER),
+            CAST(unixepoch(?2, 'subsec') * 1000 AS INTEGER)",
+        params![range.from, range.to],
+        |row| {
+            let from: Option<i64> = row.get(0)?;
+            let to: Option<i64> = row.get(1)?;
+            Ok(from.zip(to))
+        },
+    )
+}
+
+/// Splits `location/measurand[/device]` into a filter, `*` leaves a part open.
+fn parse_target(target: &str) -> Option<SeriesFilter> {
+    let mut parts = target.trim().split('/');
+    let part = |part: Option<&str>| {
+        part.map(str::trim)
+            .filter(|part| !part.is_empty() && *part != "*")
+            .map(str::to_string)
+    };
+    let location = part(parts.next());
+    let measurand = part(parts.next());
+    let device = match part(parts.next()) {
+        Some(device) => Some(device.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() || (location.is_none() && measurand.is_none() && device.is_none()) {
+        return None;
+    }
+    Some(SeriesFilter {
+        location,
+        measurand,
+        device,
+    })
+}
+
+fn adhoc_matches(filters: &[AdhocFilter], series: &api::Series) -> bool {
+    filters.iter().all(|filter| match filter.key.as_str() {
+        "location" => filter.value.eq_ignore_ascii_case(&series.location),
+        "measurand" => filter.value == series.measurand,
+        "device" => filter.value == series.device.to_string(),
+        _ => true,
+    })
+}
+
+/// The bucket width for a query: Grafana's interval, widened so no more than
+/// `max_points` buckets fit the range. Widths over an hour are rounded up to whole hours
+/// so the hourly rollups can answer them.
+fn bucket_width(from: i64, to: i64, interval_ms: Option<i64>, max_points: i64) -> i64 {
+    let width = interval_ms
+        .unwrap_or(0)
+        .max((to - from + max_points - 1) / max_points)
+        .max(1);
+    match width > HOUR_MS {
+        true => (width + HOUR_MS - 1) / HOUR_MS * HOUR_MS,
+        false => width,
+    }
+}
+
+/// Events in `from..to`, optionally only those of one location, oldest first.
+fn events(
+    conn: &Connection,
+    from: i64,
+    to: i64,
+    location: Option<&str>,
+) -> rusqlite::Result<Vec<(i64, String, String, i64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT event.timestamp, location.name, event.kind, event.channel
+        FROM event
+            JOIN location ON location.id = event.location_id
+        WHERE
+            event.timestamp >= ?1
+            AND event.timestamp < ?2
+            AND (?3 IS NULL OR location.name = ?3)
+        ORDER BY event.timestamp
+        LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(params![from, to, location, MAX_ANNOTATIONS], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_parse_into_filters() {
+        let filter = parse_target("kitchen/*").unwrap();
+        assert_eq!(Some("kitchen".to_string()), filter.location);
+        assert_eq!(None, filter.measurand);
+
+        let filter = parse_target("*/temperature/3").unwrap();
+        assert_eq!(None, filter.location);
+        assert_eq!(Some(3), filter.device);
+
+        assert!(parse_target("").is_none());
+        assert!(parse_target("*/*").is_none());
+        assert!(parse_target("kitchen/temperature/three").is_none());
+        assert!(parse_target("a/b/1/c").is_none());
+    }
+
+    #[test]
+    fn test_bucket_width_respects_max_points_and_rollups() {
+        assert_eq!(1000, bucket_width(0, 60_000, Some(1000), 1000));
+        // a day in 100 points is too many for a 1s interval
+        assert_eq!(864_000, bucket_width(0, 86_400_000, Some(1000), 100));
+        // 90 minutes become two hours so they come from the rollups
+        assert_eq!(
+            2 * HOUR_MS,
+            bucket_width(0, HOUR_MS, Some(HOUR_MS * 3 / 2), 1000)
+        );
+    }
+
+    #[test]
+    fn test_range_is_parsed_by_sqlite() {
+        let conn = Connection::open_in_memory().unwrap();
+        let range = TimeRange {
+            from: "2016-10-31T06:33:44.866Z".to_string(),
+            to: "2016-10-31T12:33:44.866Z".to_string(),
+        };
+        assert_eq!(
+            Some((1477895624866, 1477917224866)),
+            parse_range(&conn, &range).unwrap()
+        );
+        let range = TimeRange {
+            from: "yesterday".to_string(),
+            to: "2016-10-31T12:33:44.866Z".to_string(),
+        };
+        assert_eq!(None, parse_range(&conn, &range).unwrap());
+    }
+}
@@ -0,0 +1,81 @@
+//! The HTTP server, for scrapers and dashboards rather than devices.
+//!
+//! Serves `/metrics`, the read-only API (see [`crate::api`]), live readings (see
+//! [`crate::live`]) and a Grafana datasource (see [`crate::grafana`]).
+
+use std::sync::Arc;
+
+use async_sqlite::Pool;
+use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+};
+use tracing::{error, info};
+
+use crate::{
+    api, grafana,
+    live::{self, LiveReading},
+    metrics::Metrics,
+};
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// What the handlers share.
+#[derive(Clone)]
+pub(crate) struct HttpState {
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) read_pool: Arc<Pool>,
+    /// Weak so that open streams don't keep the channel alive past a shutdown.
+    pub(crate) live: broadcast::WeakSender<LiveReading>,
+}
+
+/// The running server.
+pub(crate) struct HttpTask {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+pub(crate) fn router(state: HttpState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .merge(api::router())
+        .merge(live::router())
+        .merge(grafana::router())
+        .with_state(state)
+}
+
+/// Serves `state` on `listener` until [`HttpTask::shutdown`] is called.
+pub(crate) fn spawn(listener: TcpListener, state: HttpState) -> HttpTask {
+    let (stop, stopped) = oneshot::channel();
+    let handle = tokio::spawn(async move {
+        let result = axum::serve(listener, router(state))
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await;
+        if let Err(e) = result {
+            error!(error = %e, "http server failed");
+        }
+    });
+    HttpTask { stop, handle }
+}
+
+impl HttpTask {
+    /// Stops accepting connections and waits for the requests in flight.
+    pub(crate) async fn shutdown(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.handle.await {
+            error!(error = %e, "http server task failed");
+        }
+        info!("http server stopped");
+    }
+}
+
+async fn metrics(State(state): State<HttpState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)],
+        state.metrics.encode(),
+    )
+}
@@ -0,0 +1,161 @@
+//! Classifying datagrams the server can't use.
+//!
+//! Nothing a device (or anything else on the network) sends may stop the ingest loop.
+//! Rejected datagrams are counted per source address and, if a quarantine directory is
+//! configured, their raw bytes are kept for inspection.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use interface::{ConverterError, Message, Sendable};
+use thiserror::Error;
+
+/// Sources tracked individually, anything beyond is counted under the unspecified address
+/// so a scan of the network can't grow the map without bound.
+const MAX_TRACKED_SOURCES: usize = 1024;
+/// Datagrams written to the quarantine directory per run of the server.
+const MAX_QUARANTINED: usize = 1000;
+
+#[derive(Error, Debug)]
+pub(crate) enum Rejection {
+    #[error("malformed datagram: {0}")]
+    Malformed(#[from] ConverterError),
+    #[error("datagram longer than {0} bytes")]
+    Oversized(usize),
+}
+
+impl Rejection {
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Malformed(_) => "malformed",
+            Self::Oversized(_) => "oversized",
+        }
+    }
+}
+
+/// Rejected datagrams from one source.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct SourceStats {
+    pub(crate) malformed: u64,
+    pub(crate) oversized: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct IngestStats {
+    pub(crate) sources: HashMap<IpAddr, SourceStats>,
+    pub(crate) socket_errors: u64,
+    quarantined: usize,
+}
+
+impl IngestStats {
+    /// Counts `rejection` against `ip` and returns how many of its kind came from there.
+    pub(crate) fn reject(&mut self, ip: IpAddr, rejection: &Rejection) -> u64 {
+        let ip = match self.sources.len() < MAX_TRACKED_SOURCES || self.sources.contains_key(&ip) {
+            true => ip,
+            false => IpAddr::from([0, 0, 0, 0]),
+        };
+        let stats = self.sources.entry(ip).or_default();
+        let count = match rejection {
+            Rejection::Malformed(_) => &mut stats.malformed,
+            Rejection::Oversized(_) => &mut stats.oversized,
+        };
+        *count += 1;
+        *count
+    }
+
+    /// Counts a failed receive and returns how many there were so far.
+    pub(crate) fn socket_error(&mut self) -> u64 {
+        self.socket_errors += 1;
+        self.socket_errors
+    }
+
+    /// Whether another datagram may still be quarantined.
+    pub(crate) fn take_quarantine_slot(&mut self) -> bool {
+        self.quarantined += 1;
+        self.quarantined <= MAX_QUARANTINED
+    }
+}
+
+/// Decodes the `len` bytes received into a buffer of `max_len + 1` bytes. Filling the
+/// extra byte means the datagram didn't fit and was cut off.
+pub(crate) fn decode(bytes: &[u8], max_len: usize) -> Result<Message, Rejection> {
+    if bytes.len() > max_len {
+        return Err(Rejection::Oversized(max_len));
+    }
+    Ok(Message::from_bytes(bytes)?)
+}
+
+/// The kind and protocol version of `message`, for logging.
+pub(crate) fn describe(message: &Message) -> (&'static str, &str) {
+    match message {
+        Message::Data(packet) => ("data", &packet.version),
+        Message::Event(packet) => ("event", &packet.version),
+        Message::Ack(packet) => ("ack", &packet.version),
+        Message::Command(packet) => ("command", &packet.version),
+        Message::CommandAck(ack) => ("command_ack", &ack.version),
+        Message::Log(record) => ("log", &record.version),
+    }
+}
+
+/// Logs the first few occurrences of a repeated problem and then every hundredth, so a
+/// misbehaving source can't flood the log.
+pub(crate) fn should_log(count: u64) -> bool {
+    count <= 10 || count.is_multiple_of(100)
+}
+
+/// Writes the raw bytes of a rejected datagram to `dir`.
+pub(crate) async fn quarantine(
+    dir: PathBuf,
+    addr: SocketAddr,
+    rejection: &Rejection,
+    bytes: Vec<u8>,
+) -> std::io::Result<PathBuf> {
+    let millis = crate::now_millis();
+    let source = addr.to_string().replace([':', '[', ']'], "_");
+    let path = dir.join(format!("{}-{}-{}.bin", millis, source, rejection.kind()));
+
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::write(&path, bytes).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::{AckPacket, BUFFER_SIZE};
+
+    #[test]
+    fn test_decode_classifies_rejections() {
+        let ack = AckPacket::new(1).to_bytes().unwrap();
+        assert!(decode(&ack, BUFFER_SIZE).is_ok());
+        assert!(matches!(
+            decode(&ack[..10], BUFFER_SIZE),
+            Err(Rejection::Malformed(_))
+        ));
+        assert!(matches!(
+            decode(&[0; BUFFER_SIZE + 1], BUFFER_SIZE),
+            Err(Rejection::Oversized(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejections_are_counted_per_source() {
+        let mut stats = IngestStats::default();
+        let ip = IpAddr::from([10, 0, 0, 2]);
+        let oversized = Rejection::Oversized(BUFFER_SIZE);
+
+        assert_eq!(1, stats.reject(ip, &oversized));
+        assert_eq!(2, stats.reject(ip, &oversized));
+        assert_eq!(1, stats.reject(IpAddr::from([10, 0, 0, 3]), &oversized));
+        assert_eq!(
+            SourceStats {
+                malformed: 0,
+                oversized: 2
+            },
+            stats.sources[&ip]
+        );
+    }
+}
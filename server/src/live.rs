@@ -0,0 +1,207 @@
+//! Readings pushed to clients as they are stored.
+//!
+//! Every reading `handle_data` stored is sent to a broadcast channel that clients can
+//! follow over Server-Sent Events at `/api/stream` or over a WebSocket at
+//! `/api/stream/ws`. Both take the `location`, `measurand` and `device` filters of
+//! `/api/series` and send each reading as a JSON object. A client too slow to keep up
+//! misses readings and is told how many, as an SSE `lagged` event or as a
+//! `{"lagged": n}` message.
+
+use std::convert::Infallible;
+
+use axum::{
+    Router,
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::get,
+};
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, error::RecvError};
+use tracing::debug;
+
+use crate::{api::SeriesFilter, http::HttpState};
+
+/// Readings buffered for subscribers that fall behind.
+const CAPACITY: usize = 1024;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct LiveReading {
+    pub(crate) device: u32,
+    pub(crate) location: String,
+    pub(crate) measurand: String,
+    pub(crate) unit: String,
+    /// Milliseconds since the unix epoch.
+    pub(crate) timestamp: i64,
+    pub(crate) value: f32,
+}
+
+#[derive(Serialize)]
+struct Lagged {
+    lagged: u64,
+}
+
+/// The channel stored readings are published to. Streams end once every sender is gone.
+pub(crate) fn channel() -> broadcast::Sender<LiveReading> {
+    broadcast::channel(CAPACITY).0
+}
+
+impl SeriesFilter {
+    pub(crate) fn matches(&self, reading: &LiveReading) -> bool {
+        self.location
+            .as_ref()
+            .is_none_or(|location| location.eq_ignore_ascii_case(&reading.location))
+            && self
+                .measurand
+                .as_ref()
+                .is_none_or(|measurand| *measurand == reading.measurand)
+            && self.device.is_none_or(|device| device == reading.device)
+    }
+}
+
+pub(crate) fn router() -> Router<HttpState> {
+    Router::new()
+        .route("/api/stream", get(sse))
+        .route("/api/stream/ws", get(websocket))
+}
+
+/// Subscribes to the readings, `None` once the server is shutting down.
+fn subscribe(state: &HttpState) -> Option<Receiver<LiveReading>> {
+    state.live.upgrade().map(|sender| sender.subscribe())
+}
+
+/// Waits for the next reading passing `filter`, or for news of missed ones.
+async fn next(
+    receiver: &mut Receiver<LiveReading>,
+    filter: &SeriesFilter,
+) -> Option<Result<LiveReading, u64>> {
+    loop {
+        match receiver.recv().await {
+            Ok(reading) if filter.matches(&reading) => return Some(Ok(reading)),
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => return Some(Err(missed)),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn sse(State(state): State<HttpState>, Query(filter): Query<SeriesFilter>) -> Response {
+    let Some(receiver) = subscribe(&state) else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    Sse::new(events(receiver, filter))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn events(
+    receiver: Receiver<LiveReading>,
+    filter: SeriesFilter,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        let event = match next(&mut receiver, &filter).await? {
+            Ok(reading) => Event::default().event("reading").json_data(&reading),
+            Err(missed) => Ok(Event::default().event("lagged").data(missed.to_string())),
+        };
+        // a reading always serializes, an empty event is the harmless fallback
+        Some((Ok(event.unwrap_or_default()), (receiver, filter)))
+    })
+}
+
+async fn websocket(
+    State(state): State<HttpState>,
+    Query(filter): Query<SeriesFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let Some(receiver) = subscribe(&state) else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    upgrade.on_upgrade(move |socket| forward(socket, receiver, filter))
+}
+
+async fn forward(mut socket: WebSocket, mut receiver: Receiver<LiveReading>, filter: SeriesFilter) {
+    loop {
+        let next = tokio::select! {
+            next = next(&mut receiver, &filter) => next,
+            // clients don't send anything, this only notices them going away
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let json = match next {
+            Some(Ok(reading)) => serde_json::to_string(&reading),
+            Some(Err(lagged)) => serde_json::to_string(&Lagged { lagged }),
+            None => break,
+        };
+        let Ok(json) = json else { continue };
+        if let Err(e) = socket.send(Message::Text(json.into())).await {
+            debug!(error = %e, "websocket subscriber went away");
+            break;
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(location: &str, measurand: &str, device: u32) -> LiveReading {
+        LiveReading {
+            device,
+            location: location.to_string(),
+            measurand: measurand.to_string(),
+            unit: "C".to_string(),
+            timestamp: 0,
+            value: 21.0,
+        }
+    }
+
+    #[test]
+    fn test_filter_matches_every_given_field() {
+        let filter = SeriesFilter {
+            location: Some("Kitchen".to_string()),
+            measurand: Some("temperature".to_string()),
+            device: None,
+        };
+        assert!(filter.matches(&reading("kitchen", "temperature", 1)));
+        assert!(filter.matches(&reading("kitchen", "temperature", 2)));
+        assert!(!filter.matches(&reading("kitchen", "humidity", 1)));
+        assert!(!filter.matches(&reading("hall", "temperature", 1)));
+        assert!(SeriesFilter::default().matches(&reading("hall", "humidity", 3)));
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_skip_filtered_and_hear_of_missed_readings() {
+        let (sender, mut receiver) = broadcast::channel(2);
+        let filter = SeriesFilter {
+            device: Some(2),
+            ..Default::default()
+        };
+
+        sender.send(reading("hall", "temperature", 1)).unwrap();
+        sender.send(reading("hall", "temperature", 2)).unwrap();
+        assert_eq!(
+            Some(Ok(reading("hall", "temperature", 2))),
+            next(&mut receiver, &filter).await
+        );
+
+        for _ in 0..3 {
+            sender.send(reading("hall", "temperature", 2)).unwrap();
+        }
+        assert_eq!(Some(Err(1)), next(&mut receiver, &filter).await);
+
+        drop(sender);
+        next(&mut receiver, &filter).await.unwrap().unwrap();
+        next(&mut receiver, &filter).await.unwrap().unwrap();
+        assert_eq!(None, next(&mut receiver, &filter).await);
+    }
+}
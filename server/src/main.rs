@@ -1,206 +1,898 @@
+mod alerts;
+mod api;
+mod backup;
+mod commands;
+mod config;
+mod dashboards;
+mod db;
+mod grafana;
+mod http;
+mod ingest;
+mod live;
+mod logging;
+mod metrics;
+mod notify;
+mod registry;
+mod retention;
+mod writer;
+
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
+    net::SocketAddr,
+    process::ExitCode,
     sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use async_sqlite::{JournalMode, Pool, PoolBuilder};
-use interface::{BUFFER_SIZE, InitializationPacket, NetworkPacket, Sendable};
+use alerts::Alerts;
+use async_sqlite::{
+    Pool,
+    rusqlite::{Connection, OpenFlags},
+};
+use commands::PendingCommands;
+use config::{Command, Config};
+use ingest::IngestStats;
+use interface::{
+    AckPacket, EventPacket, InitializationPacket, LogLevel as DeviceLogLevel, LogRecord, Message,
+    NetworkPacket, RegistrationResponse, STATUS_BAD_REQUEST, STATUS_FORBIDDEN, STATUS_SERVER_ERROR,
+    STATUS_UNSUPPORTED_VERSION, Sendable,
+};
+use metrics::{Metrics, ReasonLabels, StatusLabels};
+use notify::Notifier;
+use registry::{AddressLookup, Device};
 use thiserror::Error;
 use tokio::{
     io::{AsyncBufReadExt as _, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream, UdpSocket},
-    sync::Mutex,
+    sync::{Mutex, broadcast},
+    task::JoinSet,
 };
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+use writer::{Delivery, Reading, Stored, Write, WriteError, Writer};
+
+/// The only protocol version this server speaks.
+const PROTOCOL_VERSION: &str = "0.0";
+
+/// Exit status for settings that failed validation.
+const EXIT_CONFIG: u8 = 2;
+/// Exit status when requests were still running at the shutdown deadline.
+const EXIT_INCOMPLETE_SHUTDOWN: u8 = 3;
 
-type AddressLookup = Arc<Mutex<HashMap<IpAddr, InitializationPacket>>>;
+/// Pause after a failed receive or accept, a persistent error would otherwise spin the loop.
+const SOCKET_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
 #[derive(Error, Debug)]
-enum ConfigError {
+pub(crate) enum ConfigError {
     #[error("Client not properly Configured in server: {0}")]
     NotConfigured(String),
 }
 
-enum SentDataResult<T, E, ER> {
+pub(crate) enum SentDataResult<T, E, ER> {
     Ok(T),
     Err(E),
     CfgErr(ER),
 }
 
+/// What the datagram handlers share, cloned into every request.
+#[derive(Clone)]
+struct DatagramContext {
+    writer: Writer,
+    address_lookup: AddressLookup,
+    sock: Arc<UdpSocket>,
+    metrics: Arc<Metrics>,
+    max_skew: Duration,
+    /// Stored readings are published here for live subscribers.
+    live: broadcast::Sender<live::LiveReading>,
+    alerts: Arc<Alerts>,
+    pending_commands: Arc<PendingCommands>,
+}
+
+impl DatagramContext {
+    /// The registered device sending from `socket_addr`.
+    async fn device_for(&self, socket_addr: &SocketAddr) -> Result<Device, ConfigError> {
+        let address_lookup_guard = self.address_lookup.lock().await;
+        address_lookup_guard
+            .get(&socket_addr.ip())
+            .cloned()
+            .ok_or_else(|| ConfigError::NotConfigured(socket_addr.to_string()))
+    }
+}
+
+/// How the server stopped.
+enum Shutdown {
+    /// Every request finished and the database was checkpointed.
+    Clean,
+    /// The deadline passed, or cleaning up failed.
+    Incomplete,
+}
+
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
+async fn main() -> ExitCode {
     dotenv::dotenv().ok();
-    let udp_port = std::env::var("UDP_PORT").expect("Need to set UDP_PORT env variable.");
-    let tcp_port = std::env::var("TCP_PORT").expect("Need to set TCP_PORT env variable.");
+    let (config, command) = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
+    // before any command opens the file, a database left at the old default moves first
+    if let Err(e) = db::move_legacy(&config.db) {
+        error!(error = %e, "unable to move the database");
+        return ExitCode::FAILURE;
+    }
+    let config = Arc::new(config);
 
-    let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", tcp_port)).await?;
-    println!("opened tcp listener at port: {:?}", tcp_port);
+    let result = match command {
+        Command::Serve => serve(config).await,
+        command => run_command(command, &config).map(|()| Shutdown::Clean),
+    };
+    match result {
+        Ok(Shutdown::Clean) => ExitCode::SUCCESS,
+        Ok(Shutdown::Incomplete) => ExitCode::from(EXIT_INCOMPLETE_SHUTDOWN),
+        Err(e) => {
+            error!(error = %e, "server failed");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let udp_sock = UdpSocket::bind(format!("0.0.0.0:{}", udp_port)).await?;
-    println!("opened udp socket at port: {:?}", udp_port);
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM (`docker stop`).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM.");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    let mut udp_buf = [0; BUFFER_SIZE];
+async fn serve(config: Arc<Config>) -> std::io::Result<Shutdown> {
+    let tcp_listener = TcpListener::bind(config.tcp_addr).await?;
+    info!(address = %config.tcp_addr, "listening for registrations");
+
+    let udp_sock = Arc::new(UdpSocket::bind(config.udp_addr).await?);
+    info!(address = %config.udp_addr, "listening for datagrams");
+
+    let http_listener = match config.http_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!(address = %addr, "serving metrics and the api");
+            Some(listener)
+        }
+        None => None,
+    };
+    let metrics = Arc::new(Metrics::new(config.series_metrics));
 
-    let address_lookup: AddressLookup = Arc::new(Mutex::new(HashMap::new()));
+    // one byte more than allowed, so a datagram that filled it is known to be too long
+    let mut udp_buf = vec![0; config.buffer_size + 1];
+    let mut stats = IngestStats::default();
 
     let pool = Arc::new(
-        PoolBuilder::new()
-            .path("db.sqlite3")
-            .journal_mode(JournalMode::Delete)
-            .open()
+        db::open(&config.db)
             .await
             .expect("Unable to open new database pool."),
     );
+    let read_pool = Arc::new(
+        db::open_read_only(&config.db)
+            .await
+            .expect("Unable to open read only database pool."),
+    );
+    db::spawn_checkpoints(pool.clone(), &config.db);
+    retention::spawn(pool.clone(), config.retention.clone());
+    backup::spawn(read_pool.clone(), config.backup.clone());
 
-    loop {
-        tokio::select! {
-            tcp_result = tcp_listener.accept() => {
-                // accept incoming config requests
-                let (stream, socket_address) = tcp_result?;
-
-                let lookup_clone = address_lookup.clone();
-
-                // initialize the new connection
-                tokio::spawn(
-                    async move { handle_initialization(socket_address, stream, lookup_clone).await },
-                );
-            },
-            udp_result = udp_sock.recv_from(&mut udp_buf) => {
-                // accept new udp packets
-                let (len, addr) = udp_result?;
-                println!("Recieved message of length: {}, from: {}", len, addr);
-
-                let pool_clone = pool.clone();
-                let lookup_clone = address_lookup.clone();
-
-                let recieved_data = NetworkPacket::from_bytes(&udp_buf)
-                    .expect("Unable to decode bytes into a readable state.");
-
-                tokio::spawn(async move {
-                    match handle_data(&addr, pool_clone, recieved_data, lookup_clone).await {
-                        SentDataResult::Ok(_) => {},
-                        SentDataResult::Err(e) => {eprintln!("unable to recieve data due to {}", e)}
-                        SentDataResult::CfgErr(e) => {eprintln!("Client not properly configured {}", e); }
+    let devices = registry::load(&read_pool)
+        .await
+        .expect("Unable to load registered devices.");
+    info!(devices = devices.len(), "loaded registered devices");
+    let address_lookup: AddressLookup = Arc::new(Mutex::new(devices));
+
+    let alerts = Arc::new(
+        Alerts::load(pool.clone(), Notifier::new(config.notifications.clone()))
+            .await
+            .expect("Unable to load alert rules."),
+    );
+    info!(rules = alerts.rules(), "loaded alert rules");
+    let absence_task = alerts::spawn(alerts.clone());
+
+    let (writer, writer_task) = writer::spawn(pool.clone(), config.batch.clone(), metrics.clone());
+    let live = live::channel();
+    let http_task = http_listener.map(|listener| {
+        let state = http::HttpState {
+            metrics: metrics.clone(),
+            read_pool: read_pool.clone(),
+            live: live.downgrade(),
+        };
+        http::spawn(listener, state)
+    });
+    let context = DatagramContext {
+        writer: writer.clone(),
+        address_lookup: address_lookup.clone(),
+        sock: udp_sock.clone(),
+        metrics: metrics.clone(),
+        max_skew: config.max_clock_skew,
+        live,
+        alerts,
+        pending_commands: Arc::new(PendingCommands::new()),
+    };
+
+    // every request is tracked so a shutdown can wait for them
+    let mut tasks = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    async {
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    info!("shutting down, no longer accepting requests");
+                    break;
+                },
+                Some(joined) = tasks.join_next() => {
+                    if let Err(e) = joined {
+                        error!(error = %e, "request handler failed");
                     }
-                });
+                },
+                tcp_result = tcp_listener.accept() => {
+                    // accept incoming config requests
+                    let (stream, socket_address) = match tcp_result {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!(error = %e, "unable to accept registration");
+                            tokio::time::sleep(SOCKET_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+
+                    let pool_clone = pool.clone();
+                    let lookup_clone = address_lookup.clone();
+                    let config_clone = config.clone();
+                    let metrics_clone = metrics.clone();
+
+                    // initialize the new connection
+                    let span = info_span!("registration", peer = %socket_address, device = field::Empty, location = field::Empty);
+                    tasks.spawn(async move {
+                        let started = Instant::now();
+                        match handle_initialization(socket_address, stream, pool_clone, lookup_clone, config_clone, metrics_clone).await {
+                            Ok(()) => debug!(latency_us = started.elapsed().as_micros() as u64, "registration handled"),
+                            Err(e) => warn!(error = %e, "registration failed"),
+                        }
+                    }.instrument(span));
+                },
+                udp_result = udp_sock.recv_from(&mut udp_buf) => {
+                    // accept new udp packets
+                    let (len, addr) = match udp_result {
+                        Ok(received) => received,
+                        Err(e) => {
+                            metrics.receive_errors.inc();
+                            let count = stats.socket_error();
+                            if ingest::should_log(count) {
+                                warn!(error = %e, count, "unable to receive datagram");
+                            }
+                            tokio::time::sleep(SOCKET_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    // taken before anything can queue up, it dates packets from devices without a clock
+                    let received = now_millis();
+                    let started = Instant::now();
+                    metrics.datagrams.inc();
+                    let span = info_span!(
+                        "datagram",
+                        peer = %addr,
+                        bytes = len,
+                        kind = field::Empty,
+                        version = field::Empty,
+                        device = field::Empty,
+                        location = field::Empty,
+                    );
+
+                    let recieved_message = match ingest::decode(&udp_buf[..len], config.buffer_size) {
+                        Ok(message) => message,
+                        Err(rejection) => {
+                            metrics.rejected.get_or_create(&ReasonLabels { reason: rejection.kind() }).inc();
+                            let count = stats.reject(addr.ip(), &rejection);
+                            if ingest::should_log(count) {
+                                span.in_scope(|| warn!(reason = %rejection, count, "rejected datagram"));
+                            }
+                            if let Some(dir) = config.quarantine_dir.clone()
+                                && stats.take_quarantine_slot()
+                            {
+                                let bytes = udp_buf[..len.min(config.buffer_size)].to_vec();
+                                tasks.spawn(async move {
+                                    match ingest::quarantine(dir, addr, &rejection, bytes).await {
+                                        Ok(path) => debug!(path = %path.display(), "quarantined datagram"),
+                                        Err(e) => warn!(error = %e, "unable to quarantine datagram"),
+                                    }
+                                }.instrument(span));
+                            }
+                            continue;
+                        }
+                    };
+                    let (kind, version) = ingest::describe(&recieved_message);
+                    span.record("kind", kind).record("version", version);
+
+                    let pool_clone = pool.clone();
+                    let context = context.clone();
+
+                    tasks.spawn(async move {
+                        let result = match recieved_message {
+                            Message::Data(packet) => handle_data(&addr, packet, received, &context).await,
+                            Message::Event(packet) => handle_event(&addr, packet, received, &context).await,
+                            Message::CommandAck(ack) => commands::handle_ack(&addr, &pool_clone, ack, &context.address_lookup).await,
+                            Message::Log(record) => handle_log(&addr, record, received, &context).await,
+                            Message::Ack(_) | Message::Command(_) => {
+                                warn!("unexpected message, only devices receive these");
+                                SentDataResult::Ok(())
+                            }
+                        };
+                        match result {
+                            SentDataResult::Ok(_) => {},
+                            SentDataResult::Err(e) => warn!(error = %e, "unable to store datagram"),
+                            SentDataResult::CfgErr(e) => {
+                                context.metrics.unregistered.inc();
+                                warn!(error = %e, "datagram from unregistered device");
+                            }
+                        }
+
+                        // the device is listening right after it sent something, piggyback queued commands
+                        commands::deliver(&addr, &pool_clone, &context.address_lookup, &context.pending_commands, &context.sock).await;
+                        debug!(latency_us = started.elapsed().as_micros() as u64, "datagram handled");
+                    }.instrument(span));
+                }
             }
         }
     }
+    .await;
+    drop(tcp_listener);
+
+    // whatever made the loop stop, let accepted requests finish and commit their writes
+    let mut shutdown = Shutdown::Clean;
+    if !tasks.is_empty() {
+        info!(requests = tasks.len(), "waiting for requests to finish");
+    }
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(config.shutdown_timeout, drain)
+        .await
+        .is_err()
+    {
+        warn!(
+            requests = tasks.len(),
+            timeout = ?config.shutdown_timeout,
+            "requests still running at the deadline, abandoning them"
+        );
+        tasks.abort_all();
+        shutdown = Shutdown::Incomplete;
+    }
+    // the last sender is gone with the context, which ends the live streams
+    drop(context);
+    if let Some(http_task) = http_task {
+        http_task.shutdown().await;
+    }
+    // absence checks write alert states, so they stop before the pools close
+    if let Some(absence_task) = absence_task {
+        absence_task.shutdown().await;
+    }
+    writer_task.shutdown().await;
+
+    if let Err(e) = db::checkpoint(&pool).await {
+        error!(error = %e, "unable to checkpoint database");
+        shutdown = Shutdown::Incomplete;
+    }
+    for pool in [pool, read_pool] {
+        if let Err(e) = pool.close().await {
+            error!(error = %e, "unable to close database");
+            shutdown = Shutdown::Incomplete;
+        }
+    }
+    info!("shutdown complete");
+
+    Ok(shutdown)
+}
+
+/// Runs one of the maintenance commands instead of the server. All work while the
+/// server is running.
+fn run_command(command: Command, config: &Config) -> std::io::Result<()> {
+    match command {
+        Command::Serve => Ok(()),
+        Command::Backup => Connection::open(&config.db.path)
+            .map_err(backup::BackupError::from)
+            .and_then(|conn| backup::snapshot(&conn, &config.backup))
+            .map(|path| info!(path = %path.display(), "wrote database snapshot"))
+            .map_err(std::io::Error::other),
+        Command::Restore { snapshot } => Connection::open(&config.db.path)
+            .map_err(backup::BackupError::from)
+            .and_then(|mut conn| {
+                conn.busy_timeout(config.db.busy_timeout)?;
+                backup::restore(&mut conn, &snapshot, &config.backup)
+            })
+            .map(|previous| {
+                info!(
+                    snapshot = %snapshot.display(),
+                    previous = %previous.display(),
+                    "restored database, the previous contents were saved as a snapshot"
+                )
+            })
+            .map_err(std::io::Error::other),
+        Command::Dashboards { dir, url } => {
+            let url = url
+                .or_else(|| {
+                    let port = config.http_addr?.port();
+                    Some(format!("http://host.docker.internal:{port}/grafana"))
+                })
+                .ok_or_else(|| {
+                    std::io::Error::other("set --url or an http port for Grafana to query")
+                })?;
+            Connection::open_with_flags(&config.db.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(dashboards::DashboardError::from)
+                .and_then(|conn| dashboards::generate(&conn, &dir, &url))
+                .map(
+                    |panels| info!(dir = %dir.display(), url, panels, "wrote grafana provisioning"),
+                )
+                .map_err(std::io::Error::other)
+        }
+        Command::Queue {
+            device,
+            kind,
+            argument,
+        } => Connection::open(&config.db.path)
+            .map_err(commands::QueueError::from)
+            .and_then(|conn| {
+                conn.busy_timeout(config.db.busy_timeout)?;
+                commands::queue(&conn, device, &kind, argument.as_deref())
+            })
+            .map(|id| info!(command = id, device, kind, "queued command"))
+            .map_err(std::io::Error::other),
+    }
+}
+
+/// Milliseconds since the unix epoch, what every stored timestamp counts.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Picks the time to store for something a device sent at `device_timestamp` and the
+/// server received at `received`. The device's clock is trusted unless it is missing or
+/// further than `max_skew` from the server's.
+fn resolve_timestamp(device_timestamp: u64, received: u64, max_skew: Duration) -> i64 {
+    if device_timestamp == 0 {
+        return received as i64;
+    }
+    let skew = device_timestamp.abs_diff(received);
+    if skew > max_skew.as_millis() as u64 {
+        warn!(
+            skew_ms = skew,
+            "device clock is off, using receive time instead"
+        );
+        return received as i64;
+    }
+    device_timestamp as i64
+}
+
+async fn respond(
+    buf_reader: &mut BufReader<TcpStream>,
+    metrics: &Metrics,
+    response: RegistrationResponse,
+) -> std::io::Result<()> {
+    metrics
+        .registrations
+        .get_or_create(&StatusLabels {
+            status: response.status,
+        })
+        .inc();
+    if !response.is_ok() {
+        warn!(
+            status = response.status,
+            reason = %response.reason,
+            "rejecting registration"
+        );
+    }
+    let bytes = response
+        .to_bytes()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    buf_reader.write_all(&bytes).await
 }
 
 async fn handle_initialization(
     socket_addr: SocketAddr,
     stream: TcpStream,
+    pool: Arc<Pool>,
     address_lookup: AddressLookup,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
 ) -> std::io::Result<()> {
-    println!("recieving message from {}", socket_addr);
-    let mut address_lookup = address_lookup.lock().await;
-
     let mut tcp_buf = String::new();
     let mut buf_reader = BufReader::new(stream);
 
-    if address_lookup.contains_key(&socket_addr.ip()) {
-        println!("{:?} already exists in config!", socket_addr);
-        buf_reader.write_all("200".as_bytes()).await?;
-        return Ok(());
-    }
-
     // buf_reader.read_to_string(&mut tcp_buf).await?;
     while let Ok(bytes_read) = buf_reader.read_line(&mut tcp_buf).await {
         if bytes_read == 0 || tcp_buf.ends_with("\n") {
             break;
         }
     }
-    println!("message recieved: {}", tcp_buf);
+    debug!(bytes = tcp_buf.len(), "received registration");
+
+    let version = tcp_buf.split(';').next().unwrap_or_default();
+    if version != PROTOCOL_VERSION {
+        let reason = format!(
+            "unsupported protocol version {}, server speaks {}",
+            version, PROTOCOL_VERSION
+        );
+        let response =
+            RegistrationResponse::rejected(STATUS_UNSUPPORTED_VERSION, reason, now_millis());
+        return respond(&mut buf_reader, &metrics, response).await;
+    }
+
     // not the most efficient but works
-    let init_packet = InitializationPacket::from_bytes(tcp_buf.as_bytes()).unwrap();
+    let init_packet = match InitializationPacket::from_bytes(tcp_buf.as_bytes()) {
+        Ok(packet) => packet,
+        Err(e) => {
+            let response =
+                RegistrationResponse::rejected(STATUS_BAD_REQUEST, e.to_string(), now_millis());
+            return respond(&mut buf_reader, &metrics, response).await;
+        }
+    };
 
-    println!("recieved metadata: {:?}", init_packet);
+    if init_packet.units.len() != init_packet.measureands.len() {
+        let reason = format!(
+            "{} units given for {} measureands",
+            init_packet.units.len(),
+            init_packet.measureands.len()
+        );
+        let response = RegistrationResponse::rejected(STATUS_BAD_REQUEST, reason, now_millis());
+        return respond(&mut buf_reader, &metrics, response).await;
+    }
 
-    address_lookup.insert(socket_addr.ip(), init_packet);
+    // readings are stored against the lowercased location, see `handle_data`
+    let location = init_packet.location.to_lowercase();
+    Span::current().record("location", location.as_str());
+    debug!(
+        version = %init_packet.version,
+        measurands = ?init_packet.measureands,
+        units = ?init_packet.units,
+        "received metadata"
+    );
+    if let Some(allowlist) = &config.location_allowlist
+        && !allowlist.contains(&location)
+    {
+        let reason = format!("location {} is not in the allowlist", location);
+        let response = RegistrationResponse::rejected(STATUS_FORBIDDEN, reason, now_millis());
+        return respond(&mut buf_reader, &metrics, response).await;
+    }
+    match registry::ensure_location(&pool, location.clone(), config.auto_create_locations).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let reason = format!(
+                "unknown location {} and auto-creation is disabled",
+                location
+            );
+            let response = RegistrationResponse::rejected(STATUS_FORBIDDEN, reason, now_millis());
+            return respond(&mut buf_reader, &metrics, response).await;
+        }
+        Err(e) => {
+            let reason = format!("unable to store location: {}", e);
+            let response =
+                RegistrationResponse::rejected(STATUS_SERVER_ERROR, reason, now_millis());
+            return respond(&mut buf_reader, &metrics, response).await;
+        }
+    }
 
-    buf_reader.write_all("200".as_bytes()).await?;
+    // re-registrations keep their id but pick up changed metadata, e.g. a new location.
+    // the cache is only touched once the database agrees so the two never diverge
+    let (id, session) = match registry::register(&pool, &socket_addr, &init_packet).await {
+        Ok(registration) => registration,
+        Err(e) => {
+            let reason = format!("unable to store registration: {}", e);
+            let response =
+                RegistrationResponse::rejected(STATUS_SERVER_ERROR, reason, now_millis());
+            return respond(&mut buf_reader, &metrics, response).await;
+        }
+    };
+    Span::current().record("device", id);
+    let device = Device {
+        id,
+        session,
+        metadata: init_packet,
+    };
+    // held only here, datagrams of every device wait on this lock
+    let previous = address_lookup.lock().await.insert(socket_addr.ip(), device);
+    if previous.is_some() {
+        info!("device registered again");
+    } else {
+        info!("device registered");
+    }
 
-    Ok(())
+    let response = RegistrationResponse::accepted(
+        id,
+        now_millis(),
+        PROTOCOL_VERSION.to_string(),
+        config.sample_interval,
+    );
+    respond(&mut buf_reader, &metrics, response).await
 }
 
 async fn handle_data(
     socket_addr: &SocketAddr,
-    pool: std::sync::Arc<Pool>,
     packet: NetworkPacket,
-    address_lookup: AddressLookup,
-) -> SentDataResult<(), async_sqlite::Error, ConfigError> {
-    let init_packet_option = {
-        let address_lookup_guard = address_lookup.lock().await;
-        address_lookup_guard.get(&socket_addr.ip()).cloned()
+    received: u64,
+    context: &DatagramContext,
+) -> SentDataResult<(), WriteError, ConfigError> {
+    let DatagramContext {
+        writer,
+        sock,
+        metrics,
+        max_skew,
+        live,
+        alerts,
+        ..
+    } = context;
+    let Device {
+        id,
+        session,
+        metadata,
+    } = match context.device_for(socket_addr).await {
+        Ok(device) => device,
+        Err(e) => return SentDataResult::CfgErr(e),
     };
 
-    if let Some(metadata) = init_packet_option {
-        // Clone the location *once* outside the loop to be the "base" for cloning
-        let base_location = metadata.location.to_lowercase(); // metadata.location is moved here
-
-        // Iterate over `packet.data` (owned), `metadata.units` (references),
-        // and `metadata.measurands` (references).
-        // Using `.zip()` repeatedly.
-        for ((unit_ref, measureand_ref), value) in metadata
-            .units
-            .iter()
-            .zip(metadata.measureands.iter())
-            .zip(packet.data.into_iter())
-        {
-            // Clone the specific data needed for *this closure's parameters*
-            // This is the point where the `String`s are truly prepared for the query.
-            let location_param = base_location.clone(); // Clone for this specific query
-            let unit_param = unit_ref.clone(); // Clone the `&String` to an owned `String`
-            let measureand = measureand_ref.clone();
-
-            match pool
-                .conn(move |conn| {
-                    // All variables captured by this `move` closure are now owned by it.
-                    // `location_param`, `value`, `measurand_param`, `unit_param`
-                    // are now independent copies, owned by this particular closure invocation.
-                    let mut stmt = conn.prepare_cached(
-                        "INSERT INTO
-                            data (location_id, value, measurand, units)
-                        SELECT
-                            location.id,
-                            ?2,
-                            ?3,
-                            ?4
-                        FROM location
-                        WHERE
-                            location.name = ?1
-                        LIMIT 1",
-                    )?;
-
-                    println!(
-                        "Uploaded data to database! Rows affected: {}, {}, {}",
-                        value, unit_param, measureand
-                    );
+    let location = metadata.location.to_lowercase();
+    Span::current()
+        .record("device", id)
+        .record("location", location.as_str());
+    let timestamp = resolve_timestamp(packet.timestamp, received, *max_skew);
+    let sequence = packet.sequence;
+    let ack_requested = packet.ack_requested;
 
-                    stmt.execute(async_sqlite::rusqlite::params![
-                        location_param, // Now owned by the params! call
-                        value,          // Copy (f64)
-                        measureand,
-                        unit_param, // Now owned by the params! call
-                    ])
-                })
-                .await
-            {
-                Ok(rows) => {
-                    if rows != 1 {
-                        eprintln!("Warning: {} rows affected", rows)
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error uploading data to database: {:?}", e);
-                    return SentDataResult::Err(e);
-                }
-            }
+    // the packet carries a value per registered measurand, anything past that is padding
+    let readings: Vec<Reading> = metadata
+        .units
+        .into_iter()
+        .zip(metadata.measureands)
+        .zip(packet.data)
+        .map(|((unit, measurand), value)| Reading {
+            measurand,
+            unit,
+            value,
+        })
+        .collect();
+    let expected = readings.len();
+
+    let rows = match writer
+        .write(Write::Readings {
+            device_id: id,
+            location: location.clone(),
+            timestamp,
+            readings: readings.clone(),
+            delivery: ack_requested.then_some(Delivery { session, sequence }),
+        })
+        .await
+    {
+        Ok(Stored::Rows(rows)) => rows,
+        Ok(Stored::Duplicate) => {
+            debug!(sequence, "readings already stored, acknowledging again");
+            send_ack(sock, socket_addr, sequence).await;
+            return SentDataResult::Ok(());
+        }
+        Err(e) => return SentDataResult::Err(e),
+    };
+    metrics.seen(id, &location, timestamp);
+
+    // a missing ack makes the client retransmit, so only send one if every reading
+    // landed. Subscribers and alerts see the packet only then, too
+    if rows != expected {
+        warn!(
+            rows,
+            expected, "not every reading was stored, not acknowledging"
+        );
+        return SentDataResult::Ok(());
+    }
+    debug!(readings = rows, sequence, "stored readings");
+
+    for reading in readings {
+        metrics.reading(
+            id,
+            &location,
+            &reading.measurand,
+            &reading.unit,
+            reading.value,
+        );
+        let reading = live::LiveReading {
+            device: id,
+            location: location.clone(),
+            measurand: reading.measurand,
+            unit: reading.unit,
+            timestamp,
+            value: reading.value,
+        };
+        alerts.observe(&reading).await;
+        // an error only means nobody is listening
+        let _ = live.send(reading);
+    }
+
+    if ack_requested {
+        send_ack(sock, socket_addr, sequence).await;
+    }
+    SentDataResult::Ok(())
+}
+
+async fn handle_event(
+    socket_addr: &SocketAddr,
+    packet: EventPacket,
+    received: u64,
+    context: &DatagramContext,
+) -> SentDataResult<(), WriteError, ConfigError> {
+    let DatagramContext {
+        writer,
+        sock,
+        metrics,
+        max_skew,
+        ..
+    } = context;
+    let Device {
+        id,
+        session,
+        metadata,
+    } = match context.device_for(socket_addr).await {
+        Ok(device) => device,
+        Err(e) => return SentDataResult::CfgErr(e),
+    };
+
+    let location = metadata.location.to_lowercase();
+    Span::current()
+        .record("device", id)
+        .record("location", location.as_str());
+
+    let sequence = packet.sequence;
+    let timestamp = resolve_timestamp(packet.timestamp, received, *max_skew);
+    metrics.seen(id, &location, timestamp);
+    let write = Write::Event {
+        device_id: id,
+        session,
+        sequence,
+        location,
+        timestamp,
+        // devices without a clock send 0, store those as NULL
+        device_timestamp: (packet.timestamp != 0).then_some(packet.timestamp as i64),
+        kind: packet.kind.as_str(),
+        channel: packet.channel,
+        payload: packet.payload,
+    };
+
+    let rows = match writer.write(write).await {
+        Ok(Stored::Rows(rows)) => rows,
+        Ok(Stored::Duplicate) => {
+            debug!(sequence, "event already stored, acknowledging again");
+            send_ack(sock, socket_addr, sequence).await;
+            return SentDataResult::Ok(());
+        }
+        Err(e) => return SentDataResult::Err(e),
+    };
+
+    // only acknowledge events that were stored so the client keeps retrying otherwise
+    if rows != 1 {
+        warn!(rows, "event was not stored, not acknowledging");
+        return SentDataResult::Ok(());
+    }
+
+    debug!(sequence, "stored event");
+    send_ack(sock, socket_addr, sequence).await;
+
+    SentDataResult::Ok(())
+}
+
+async fn handle_log(
+    socket_addr: &SocketAddr,
+    record: LogRecord,
+    received: u64,
+    context: &DatagramContext,
+) -> SentDataResult<(), WriteError, ConfigError> {
+    let DatagramContext {
+        writer,
+        sock,
+        metrics,
+        ..
+    } = context;
+    let device = match context.device_for(socket_addr).await {
+        Ok(device) => device,
+        Err(e) => return SentDataResult::CfgErr(e),
+    };
+
+    let location = device.metadata.location.to_lowercase();
+    Span::current()
+        .record("device", device.id)
+        .record("location", location.as_str());
+    metrics.seen(device.id, &location, received as i64);
+    // logs may have waited on the device for a while, so they are filed by receive time
+    let sequence = record.sequence;
+    let write = Write::Log {
+        device_id: device.id,
+        session: device.session,
+        sequence,
+        timestamp: received as i64,
+        device_timestamp: (record.timestamp != 0).then_some(record.timestamp as i64),
+        level: record.level.as_str(),
+        target: record.target.clone(),
+        message: record.message.clone(),
+    };
+
+    // the device keeps the record buffered until it sees the ack
+    match writer.write(write).await {
+        Ok(Stored::Rows(_)) => {}
+        Ok(Stored::Duplicate) => {
+            debug!(sequence, "log record already stored, acknowledging again");
+            send_ack(sock, socket_addr, sequence).await;
+            return SentDataResult::Ok(());
+        }
+        Err(e) => return SentDataResult::Err(e),
+    }
+    // echoed at the device's own level so the server log can be filtered the same way
+    let (target, message) = (&record.target, &record.message);
+    match record.level {
+        DeviceLogLevel::Error => error!(device_target = %target, "{}", message),
+        DeviceLogLevel::Warn => warn!(device_target = %target, "{}", message),
+        DeviceLogLevel::Info => info!(device_target = %target, "{}", message),
+        DeviceLogLevel::Debug | DeviceLogLevel::Trace => {
+            debug!(device_target = %target, "{}", message)
+        }
+    }
+    send_ack(sock, socket_addr, sequence).await;
+    SentDataResult::Ok(())
+}
+
+async fn send_ack(sock: &UdpSocket, socket_addr: &SocketAddr, sequence: u32) {
+    let bytes = match AckPacket::new(sequence).to_bytes() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!(error = %e, "unable to encode ack");
+            return;
+        }
+    };
+    if let Err(e) = sock.send_to(&bytes, socket_addr).await {
+        warn!(error = %e, sequence, "unable to send ack");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECEIVED: u64 = 1_700_000_000_000;
+    const MAX_SKEW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_devices_without_clock_get_receive_time() {
+        assert_eq!(RECEIVED as i64, resolve_timestamp(0, RECEIVED, MAX_SKEW));
+    }
+
+    #[test]
+    fn test_device_clock_is_trusted_within_max_skew() {
+        for device in [
+            RECEIVED - 60_000,
+            RECEIVED - 1,
+            RECEIVED + 1,
+            RECEIVED + 60_000,
+        ] {
+            assert_eq!(device as i64, resolve_timestamp(device, RECEIVED, MAX_SKEW));
+        }
+    }
+
+    #[test]
+    fn test_device_clock_past_max_skew_is_replaced() {
+        // behind, e.g. a record that waited in the device's buffer, and ahead
+        for device in [RECEIVED - 60_001, 1, RECEIVED + 60_001, u64::MAX] {
+            assert_eq!(
+                RECEIVED as i64,
+                resolve_timestamp(device, RECEIVED, MAX_SKEW)
+            );
         }
-        SentDataResult::Ok(())
-    } else {
-        eprintln!("{} not found in hashmap!", socket_addr);
-        return SentDataResult::CfgErr(ConfigError::NotConfigured(socket_addr.to_string()));
     }
 }
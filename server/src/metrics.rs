@@ -0,0 +1,211 @@
+//! Counters and gauges about the server and the devices it serves, for Prometheus.
+//!
+//! Everything is kept in memory and rendered in the OpenMetrics text format on every
+//! scrape of `/metrics`. Counters start from zero when the server restarts, which
+//! Prometheus' `rate()` handles.
+
+use std::sync::atomic::AtomicU64;
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ReasonLabels {
+    pub(crate) reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct StatusLabels {
+    pub(crate) status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DeviceLabels {
+    device: u32,
+    location: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SeriesLabels {
+    device: u32,
+    location: String,
+    measurand: String,
+    unit: String,
+}
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) datagrams: Counter,
+    pub(crate) receive_errors: Counter,
+    pub(crate) rejected: Family<ReasonLabels, Counter>,
+    pub(crate) unregistered: Counter,
+    pub(crate) registrations: Family<StatusLabels, Counter>,
+    pub(crate) db_errors: Counter,
+    pub(crate) write_latency: Histogram,
+    last_seen: Family<DeviceLabels, Gauge<f64, AtomicU64>>,
+    /// Absent unless the latest readings are exported.
+    series: Option<Family<SeriesLabels, Gauge<f64, AtomicU64>>>,
+}
+
+impl Metrics {
+    /// Registers every metric, `series` exports the latest value of every series as well.
+    pub(crate) fn new(series: bool) -> Self {
+        let mut registry = Registry::with_prefix("sensors");
+
+        let datagrams = Counter::default();
+        registry.register(
+            "datagrams_received",
+            "Datagrams received on the UDP socket",
+            datagrams.clone(),
+        );
+        let receive_errors = Counter::default();
+        registry.register(
+            "receive_errors",
+            "Failed receives on the UDP socket",
+            receive_errors.clone(),
+        );
+        let rejected = Family::default();
+        registry.register(
+            "datagrams_rejected",
+            "Datagrams that could not be decoded, by reason",
+            rejected.clone(),
+        );
+        let unregistered = Counter::default();
+        registry.register(
+            "unregistered_datagrams",
+            "Datagrams from addresses no device registered from",
+            unregistered.clone(),
+        );
+        let registrations = Family::default();
+        registry.register(
+            "registrations",
+            "Registration attempts, by response status",
+            registrations.clone(),
+        );
+        let db_errors = Counter::default();
+        registry.register(
+            "db_errors",
+            "Writes that failed to be stored",
+            db_errors.clone(),
+        );
+        // 0.5 ms up to about a second
+        let write_latency = Histogram::new(exponential_buckets(0.0005, 2.0, 12));
+        registry.register(
+            "db_write_seconds",
+            "Time taken to commit a batch of writes",
+            write_latency.clone(),
+        );
+        let last_seen = Family::default();
+        registry.register(
+            "device_last_seen_seconds",
+            "Unix time a device was last heard from",
+            last_seen.clone(),
+        );
+        let series = series.then(|| {
+            let family = Family::default();
+            registry.register(
+                "reading",
+                "Latest value stored for a series",
+                family.clone(),
+            );
+            family
+        });
+
+        Self {
+            registry,
+            datagrams,
+            receive_errors,
+            rejected,
+            unregistered,
+            registrations,
+            db_errors,
+            write_latency,
+            last_seen,
+            series,
+        }
+    }
+
+    /// Records that `device` sent something at `timestamp`, in milliseconds.
+    pub(crate) fn seen(&self, device: u32, location: &str, timestamp: i64) {
+        let labels = DeviceLabels {
+            device,
+            location: location.to_string(),
+        };
+        self.last_seen
+            .get_or_create(&labels)
+            .set(timestamp as f64 / 1000.0);
+    }
+
+    /// Records the latest reading of a series, if those are exported.
+    pub(crate) fn reading(
+        &self,
+        device: u32,
+        location: &str,
+        measurand: &str,
+        unit: &str,
+        value: f32,
+    ) {
+        let Some(series) = &self.series else {
+            return;
+        };
+        let labels = SeriesLabels {
+            device,
+            location: location.to_string(),
+            measurand: measurand.to_string(),
+            unit: unit.to_string(),
+        };
+        series.get_or_create(&labels).set(f64::from(value));
+    }
+
+    /// Renders every metric in the OpenMetrics text format.
+    pub(crate) fn encode(&self) -> String {
+        let mut text = String::new();
+        // writing into a String can't fail
+        let _ = encode(&mut text, &self.registry);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodes_labeled_metrics() {
+        let metrics = Metrics::new(true);
+        metrics.datagrams.inc();
+        metrics
+            .registrations
+            .get_or_create(&StatusLabels { status: 200 })
+            .inc();
+        metrics.seen(3, "kitchen", 1_500);
+        metrics.reading(3, "kitchen", "temperature", "C", 21.5);
+
+        let text = metrics.encode();
+        assert!(text.contains("sensors_datagrams_received_total 1\n"));
+        assert!(text.contains("sensors_registrations_total{status=\"200\"} 1\n"));
+        assert!(
+            text.contains(
+                "sensors_device_last_seen_seconds{device=\"3\",location=\"kitchen\"} 1.5\n"
+            )
+        );
+        assert!(text.contains(
+            "sensors_reading{device=\"3\",location=\"kitchen\",measurand=\"temperature\",unit=\"C\"} 21.5\n"
+        ));
+    }
+
+    #[test]
+    fn test_series_can_be_left_out() {
+        let metrics = Metrics::new(false);
+        metrics.reading(3, "kitchen", "temperature", "C", 21.5);
+        assert!(!metrics.encode().contains("sensors_reading"));
+    }
+}
@@ -0,0 +1,602 @@
+//! Notifications about alerts firing and resolving.
+//!
+//! Every transition of an alert (see [`crate::alerts`]) is sent to each configured sink:
+//! a webhook receiving JSON, an email, an ntfy or Gotify push, or a local command. The
+//! title and message are templates in which `{rule}`, `{status}`, `{location}`,
+//! `{measurand}`, `{device}`, `{value}` and `{timestamp}` are replaced. Failed deliveries
+//! are retried with a doubling delay of at most five minutes, deliveries still retrying
+//! when the server stops are dropped. In dry run mode nothing is sent, notifications are
+//! only logged.
+
+use std::{path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    transport::smtp::authentication::Credentials,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::alerts::{Status, Transition};
+
+/// How long a single attempt may take.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest delay between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Error, Debug)]
+pub(crate) enum NotifyError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Invalid email: {0}")]
+    Email(#[from] lettre::error::Error),
+    #[error("Invalid email address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Command failed: {0}")]
+    Command(String),
+}
+
+/// Where notifications go, one `[[notifications.sinks]]` table of the config file each.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum SinkSettings {
+    /// POSTs the notification as JSON.
+    Webhook { url: String },
+    /// Publishes the message to an ntfy topic url.
+    Ntfy { url: String, token: Option<String> },
+    /// Sends the message to a Gotify server with an application token.
+    Gotify { url: String, token: String },
+    /// Mails the message through an SMTP server.
+    Email {
+        host: String,
+        /// The default of the security mode if unset.
+        port: Option<u16>,
+        #[serde(default)]
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    /// Runs a program with templated arguments, the notification is in its environment
+    /// as `ALERT_RULE`, `ALERT_STATUS`, `ALERT_TITLE`, `ALERT_MESSAGE` and so on.
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SmtpSecurity {
+    /// Upgrade a plain connection, port 587 by default.
+    #[default]
+    Starttls,
+    /// TLS from the start, port 465 by default.
+    Tls,
+    /// Plain text, port 25 by default. Only for a relay on the local machine.
+    None,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct NotifySettings {
+    pub(crate) sinks: Vec<SinkSettings>,
+    pub(crate) title: String,
+    pub(crate) message: String,
+    /// Attempts after the first before a delivery is given up.
+    pub(crate) retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub(crate) backoff: Duration,
+    /// Log notifications instead of sending them.
+    pub(crate) dry_run: bool,
+}
+
+/// A transition with its rendered title and message, the JSON webhooks receive.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct Notification {
+    rule: String,
+    status: &'static str,
+    device: u32,
+    location: String,
+    measurand: String,
+    /// Milliseconds since the unix epoch.
+    timestamp: i64,
+    value: Option<f64>,
+    title: String,
+    message: String,
+}
+
+/// Cheap to clone handle delivering notifications in the background.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    settings: Arc<NotifySettings>,
+    client: reqwest::Client,
+}
+
+impl SinkSettings {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Webhook { .. } => "webhook",
+            Self::Ntfy { .. } => "ntfy",
+            Self::Gotify { .. } => "gotify",
+            Self::Email { .. } => "email",
+            Self::Command { .. } => "command",
+        }
+    }
+}
+
+impl Notifier {
+    pub(crate) fn new(settings: NotifySettings) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            settings: Arc::new(settings),
+            client,
+        }
+    }
+
+    /// Starts delivering `transition` to every sink without waiting for them.
+    pub(crate) fn notify(&self, transition: &Transition) {
+        let notification = Arc::new(self.render(transition));
+        for index in 0..self.settings.sinks.len() {
+            let notifier = self.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                notifier
+                    .deliver(&notifier.settings.sinks[index], &notification)
+                    .await
+            });
+        }
+    }
+
+    fn render(&self, transition: &Transition) -> Notification {
+        let mut notification = Notification {
+            rule: transition.rule.clone(),
+            status: match transition.status {
+                Status::Firing => "firing",
+                Status::Resolved => "resolved",
+            },
+            device: transition.device,
+            location: transition.location.clone(),
+            measurand: transition.measurand.clone(),
+            timestamp: transition.timestamp,
+            value: transition.value,
+            title: String::new(),
+            message: String::new(),
+        };
+        notification.title = render(&self.settings.title, &notification);
+        notification.message = render(&self.settings.message, &notification);
+        notification
+    }
+
+    /// Sends to one sink, retrying until it worked or the retries are used up.
+    async fn deliver(&self, sink: &SinkSettings, notification: &Notification) {
+        let kind = sink.kind();
+        if self.settings.dry_run {
+            info!(
+                sink = kind,
+                title = notification.title,
+                text = notification.message,
+                "dry run, not sending notification"
+            );
+            return;
+        }
+
+        let mut delay = self.settings.backoff.min(MAX_BACKOFF);
+        for attempt in 0..=self.settings.retries {
+            match send(&self.client, sink, notification).await {
+                Ok(()) => {
+                    info!(
+                        sink = kind,
+                        rule = notification.rule,
+                        status = notification.status,
+                        "sent notification"
+                    );
+                    return;
+                }
+                Err(e) if attempt < self.settings.retries => {
+                    warn!(sink = kind, error = %e, attempt, "unable to send notification, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2).min(MAX_BACKOFF);
+                }
+                Err(e) => error!(
+                    sink = kind,
+                    error = %e,
+                    rule = notification.rule,
+                    "unable to send notification, giving up"
+                ),
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    sink: &SinkSettings,
+    notification: &Notification,
+) -> Result<(), NotifyError> {
+    let firing = notification.status == "firing";
+    match sink {
+        SinkSettings::Webhook { url } => {
+            client
+                .post(url)
+                .json(notification)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        SinkSettings::Ntfy { url, token } => {
+            let mut request = client
+                .post(url)
+                .header("Title", &notification.title)
+                .header("Priority", if firing { "high" } else { "default" })
+                .header(
+                    "Tags",
+                    if firing {
+                        "warning"
+                    } else {
+                        "white_check_mark"
+                    },
+                )
+                .body(notification.message.clone());
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send().await?.error_for_status()?;
+        }
+        SinkSettings::Gotify { url, token } => {
+            client
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token)
+                .json(&serde_json::json!({
+                    "title": notification.title,
+                    "message": notification.message,
+                    "priority": if firing { 8 } else { 4 },
+                }))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        SinkSettings::Email {
+            host,
+            port,
+            security,
+            username,
+            password,
+            from,
+            to,
+        } => {
+            let mut transport = match security {
+                SmtpSecurity::Starttls => {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+                }
+                SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+                SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            };
+            if let Some(port) = port {
+                transport = transport.port(*port);
+            }
+            if let (Some(username), Some(password)) = (username, password) {
+                transport =
+                    transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            let mut email = Message::builder()
+                .from(from.parse()?)
+                .subject(&notification.title);
+            for to in to {
+                email = email.to(to.parse()?);
+            }
+            let email = email.body(notification.message.clone())?;
+            transport
+                .timeout(Some(SEND_TIMEOUT))
+                .build()
+                .send(email)
+                .await?;
+        }
+        SinkSettings::Command { program, args } => {
+            let mut command = tokio::process::Command::new(program);
+            command
+                .args(args.iter().map(|arg| render(arg, notification)))
+                .env("ALERT_RULE", &notification.rule)
+                .env("ALERT_STATUS", notification.status)
+                .env("ALERT_DEVICE", notification.device.to_string())
+                .env("ALERT_LOCATION", &notification.location)
+                .env("ALERT_MEASURAND", &notification.measurand)
+                .env("ALERT_TIMESTAMP", notification.timestamp.to_string())
+                .env("ALERT_VALUE", value(notification.value))
+                .env("ALERT_TITLE", &notification.title)
+                .env("ALERT_MESSAGE", &notification.message)
+                .stdin(Stdio::null())
+                .kill_on_drop(true);
+            let status = tokio::time::timeout(SEND_TIMEOUT, command.status())
+                .await
+                .map_err(|_| NotifyError::Command("timed out".to_string()))??;
+            if !status.success() {
+                return Err(NotifyError::Command(status.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the placeholders in `template`, unknown ones are left as they are.
+///
+/// The template is scanned once, so placeholders within substituted values stay as they
+/// are.
+fn render(template: &str, notification: &Notification) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let replaced = rest
+            .find('}')
+            .and_then(|end| Some((end, placeholder(&rest[1..end], notification)?)));
+        match replaced {
+            Some((end, replacement)) => {
+                rendered.push_str(&replacement);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The replacement of the placeholder `name`, `None` for unknown ones.
+fn placeholder(name: &str, notification: &Notification) -> Option<String> {
+    Some(match name {
+        "rule" => notification.rule.clone(),
+        "status" => notification.status.to_string(),
+        "device" => notification.device.to_string(),
+        "location" => notification.location.clone(),
+        "measurand" => notification.measurand.clone(),
+        "timestamp" => notification.timestamp.to_string(),
+        "value" => value(notification.value),
+        _ => return None,
+    })
+}
+
+/// The value to two decimals, `-` for alerts without one.
+fn value(value: Option<f64>) -> String {
+    match value {
+        Some(value) => ((value * 100.0).round() / 100.0).to_string(),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{Router, extract::State, http::HeaderMap, http::StatusCode};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    fn transition(status: Status) -> Transition {
+        Transition {
+            rule: "freezer warm".to_string(),
+            status,
+            device: 3,
+            location: "basement".to_string(),
+            measurand: "temperature".to_string(),
+            timestamp: 1_500,
+            value: Some(-8.123),
+        }
+    }
+
+    fn notifier(sinks: Vec<SinkSettings>, dry_run: bool) -> Notifier {
+        Notifier::new(NotifySettings {
+            sinks,
+            title: "{rule} {status}".to_string(),
+            message: "{measurand} in {location} is {value}".to_string(),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            dry_run,
+        })
+    }
+
+    /// An HTTP server recording every request, answering with `statuses` in turn and
+    /// 200 once they ran out.
+    async fn http_stand_in(statuses: Vec<u16>) -> (String, Received) {
+        let received = Received::default();
+        let statuses = Arc::new(Mutex::new(statuses));
+        let app = Router::new()
+            .fallback(
+                |State((received, statuses)): State<(Received, Arc<Mutex<Vec<u16>>>)>,
+                 headers: HeaderMap,
+                 body: String| async move {
+                    received.lock().unwrap().push((headers, body));
+                    let mut statuses = statuses.lock().unwrap();
+                    let status = match statuses.is_empty() {
+                        true => 200,
+                        false => statuses.remove(0),
+                    };
+                    StatusCode::from_u16(status).unwrap()
+                },
+            )
+            .with_state((received.clone(), statuses));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    /// Just enough of an SMTP server to accept one mail, returns what was in its DATA.
+    async fn smtp_stand_in() -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.to_uppercase().get(..4) {
+                    Some("EHLO") => b"250 localhost\r\n",
+                    Some("DATA") => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    Some("QUIT") => {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_templates_are_rendered() {
+        let notification = notifier(vec![], false).render(&transition(Status::Firing));
+        assert_eq!("freezer warm firing", notification.title);
+        assert_eq!("temperature in basement is -8.12", notification.message);
+
+        let mut absent = transition(Status::Resolved);
+        absent.value = None;
+        let notification = notifier(vec![], false).render(&absent);
+        assert_eq!("temperature in basement is -", notification.message);
+
+        let mut odd = transition(Status::Firing);
+        odd.rule = "{location} {".to_string();
+        let notification = notifier(vec![], false).render(&odd);
+        assert_eq!("{location} { firing", notification.title);
+        assert_eq!(
+            "{unknown} {basement} basement",
+            render("{unknown} {{location}} {location}", &notification)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_is_retried_until_accepted() {
+        let (url, received) = http_stand_in(vec![500, 503]).await;
+        let sink = SinkSettings::Webhook { url };
+        let notifier = notifier(vec![sink.clone()], false);
+        let notification = notifier.render(&transition(Status::Firing));
+        notifier.deliver(&sink, &notification).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(3, received.len());
+        let body: serde_json::Value = serde_json::from_str(&received[2].1).unwrap();
+        assert_eq!("freezer warm", body["rule"]);
+        assert_eq!("firing", body["status"]);
+        assert_eq!("freezer warm firing", body["title"]);
+    }
+
+    #[tokio::test]
+    async fn test_push_sinks_send_title_and_token() {
+        let (url, received) = http_stand_in(vec![]).await;
+        let ntfy = SinkSettings::Ntfy {
+            url: format!("{url}/freezer"),
+            token: Some("secret".to_string()),
+        };
+        let gotify = SinkSettings::Gotify {
+            url: format!("{url}/"),
+            token: "app".to_string(),
+        };
+        let notifier = notifier(vec![], false);
+        let notification = notifier.render(&transition(Status::Firing));
+        notifier.deliver(&ntfy, &notification).await;
+        notifier.deliver(&gotify, &notification).await;
+
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        assert_eq!("freezer warm firing", headers["title"]);
+        assert_eq!("high", headers["priority"]);
+        assert_eq!("Bearer secret", headers["authorization"]);
+        assert_eq!("temperature in basement is -8.12", body);
+        let (headers, body) = &received[1];
+        assert_eq!("app", headers["x-gotify-key"]);
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(8, body["priority"]);
+    }
+
+    #[tokio::test]
+    async fn test_email_is_sent_over_smtp() {
+        let (port, mail) = smtp_stand_in().await;
+        let sink = SinkSettings::Email {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "sensors@localhost".to_string(),
+            to: vec!["me@localhost".to_string()],
+        };
+        let notifier = notifier(vec![], false);
+        let notification = notifier.render(&transition(Status::Resolved));
+        notifier.deliver(&sink, &notification).await;
+
+        let mail = mail.await.unwrap();
+        assert!(mail.contains("Subject: freezer warm resolved"));
+        assert!(mail.contains("To: me@localhost"));
+        assert!(mail.contains("temperature in basement is -8.12"));
+    }
+
+    #[tokio::test]
+    async fn test_command_gets_arguments_and_environment() {
+        let path = std::env::temp_dir().join(format!("server-notify-{}.txt", std::process::id()));
+        let sink = SinkSettings::Command {
+            program: "sh".into(),
+            args: vec![
+                "-c".to_string(),
+                "echo \"$1 $ALERT_STATUS $ALERT_VALUE\" > \"$2\"".to_string(),
+                "sh".to_string(),
+                "{rule}".to_string(),
+                path.display().to_string(),
+            ],
+        };
+        let notifier = notifier(vec![], false);
+        let notification = notifier.render(&transition(Status::Firing));
+        notifier.deliver(&sink, &notification).await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("freezer warm firing -8.12\n", written);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_sends_nothing() {
+        let (url, received) = http_stand_in(vec![]).await;
+        let sink = SinkSettings::Webhook { url };
+        let notifier = notifier(vec![sink.clone()], true);
+        let notification = notifier.render(&transition(Status::Firing));
+        notifier.deliver(&sink, &notification).await;
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! Registered devices, cached in memory and persisted in the `device` table so a
+//! restarted server still recognises devices that registered before it went down.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use async_sqlite::Pool;
+use interface::InitializationPacket;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+pub(crate) type AddressLookup = Arc<Mutex<HashMap<IpAddr, Device>>>;
+
+/// A registered client and the metadata it sent when registering.
+#[derive(Debug, Clone)]
+pub(crate) struct Device {
+    pub(crate) id: u32,
+    /// Counts the registrations of the device, its packet sequence starts over with each.
+    pub(crate) session: u32,
+    pub(crate) metadata: InitializationPacket,
+}
+
+pub(crate) fn split_list(list: String) -> Vec<String> {
+    list.split(',').map(|v| v.to_string()).collect()
+}
+
+/// Reads every persisted device, keyed by the address it registered from.
+pub async fn load(pool: &Pool) -> Result<HashMap<IpAddr, Device>, async_sqlite::Error> {
+    let rows = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT
+                    id, address, version, location, data_map, units, measureands, session
+                FROM device",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(1)?,
+                    Device {
+                        id: row.get(0)?,
+                        session: row.get(7)?,
+                        metadata: InitializationPacket {
+                            version: row.get(2)?,
+                            location: row.get(3)?,
+                            data_map: split_list(row.get(4)?),
+                            units: split_list(row.get(5)?),
+                            measureands: split_list(row.get(6)?),
+                        },
+                    },
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+
+    let mut devices = HashMap::new();
+    for (address, device) in rows {
+        match address.parse::<IpAddr>() {
+            Ok(ip) => {
+                devices.insert(ip, device);
+            }
+            Err(e) => warn!(
+                error = %e,
+                device = device.id,
+                address,
+                "skipping device with bad address"
+            ),
+        }
+    }
+    Ok(devices)
+}
+
+/// Makes sure a location called `name` exists, creating it if `create` is set.
+///
+/// Returns whether the location exists afterwards.
+pub async fn ensure_location(
+    pool: &Pool,
+    name: String,
+    create: bool,
+) -> Result<bool, async_sqlite::Error> {
+    pool.conn(move |conn| {
+        if create {
+            conn.execute(
+                "INSERT INTO location (name) VALUES (?1) ON CONFLICT (name) DO NOTHING",
+                [&name],
+            )?;
+        }
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM location WHERE name = ?1)",
+            [&name],
+            |row| row.get(0),
+        )
+    })
+    .await
+}
+
+/// Stores the registration of `socket_addr`, updating the metadata of a known address.
+///
+/// Returns the id of the device, which stays the same across re-registrations, and the
+/// session the registration starts.
+pub async fn register(
+    pool: &Pool,
+    socket_addr: &SocketAddr,
+    metadata: &InitializationPacket,
+) -> Result<(u32, u32), async_sqlite::Error> {
+    let address = socket_addr.ip().to_string();
+    let version = metadata.version.clone();
+    let location = metadata.location.clone();
+    let data_map = metadata.data_map.join(",");
+    let units = metadata.units.join(",");
+    let measureands = metadata.measureands.join(",");
+
+    pool.conn(move |conn| {
+        let mut stmt = conn.prepare_cached(
+            "INSERT INTO
+                device (address, version, location, data_map, units, measureands)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (address) DO UPDATE SET
+                version = excluded.version,
+                location = excluded.location,
+                data_map = excluded.data_map,
+                units = excluded.units,
+                measureands = excluded.measureands,
+                updated = strftime('%s', 'now'),
+                session = session + 1
+            RETURNING id, session",
+        )?;
+        stmt.query_row(
+            async_sqlite::rusqlite::params![
+                address,
+                version,
+                location,
+                data_map,
+                units,
+                measureands
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    })
+    .await
+}
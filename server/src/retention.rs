@@ -0,0 +1,401 @@
+//! Downsampling and pruning of stored readings.
+//!
+//! A background job aggregates complete hours of `sample` into `rollup_hourly` and complete
+//! days of those into `rollup_daily`, then deletes rows older than the `retention_policy`
+//! allows. Rows are only ever deleted once they are covered by the next coarser rollup.
+//! The policies are `[[retention.policies]]` of the config file, without any nothing is
+//! deleted.
+
+use std::{sync::Arc, time::Duration};
+
+use async_sqlite::{
+    Pool,
+    rusqlite::{self, Connection, params},
+};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+pub(crate) const HOUR_MS: i64 = 60 * 60 * 1000;
+pub(crate) const DAY_MS: i64 = 24 * HOUR_MS;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RetentionSettings {
+    /// How often the job runs, zero disables it.
+    pub(crate) interval: Duration,
+    /// Buckets are only aggregated once they ended this long ago, so samples dated by
+    /// a device clock that is a little behind still land in an open bucket.
+    pub(crate) settle: Duration,
+    /// Written to `retention_policy` when the job starts, replacing what is there.
+    pub(crate) policies: Vec<RetentionPolicy>,
+}
+
+/// How long rows are kept, in days, one `[[retention.policies]]` table of the config
+/// file each. Unset days keep rows forever. A series uses the most specific policy:
+/// location and measurand, then measurand, then location, then the one without either.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetentionPolicy {
+    pub(crate) location: Option<String>,
+    pub(crate) measurand: Option<String>,
+    pub(crate) raw_days: Option<u32>,
+    pub(crate) hourly_days: Option<u32>,
+    pub(crate) daily_days: Option<u32>,
+}
+
+/// What one run of the job did.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Summary {
+    pub(crate) hourly: usize,
+    pub(crate) daily: usize,
+    pub(crate) pruned_samples: usize,
+    pub(crate) pruned_hourly: usize,
+    pub(crate) pruned_daily: usize,
+}
+
+pub(crate) fn spawn(pool: Arc<Pool>, settings: RetentionSettings) -> Option<JoinHandle<()>> {
+    if settings.interval.is_zero() {
+        return None;
+    }
+
+    let mut interval = tokio::time::interval(settings.interval);
+    Some(tokio::spawn(async move {
+        let policies = settings.policies.clone();
+        match pool
+            .conn_mut(move |conn| save_policies(conn, &policies))
+            .await
+        {
+            Ok(()) => info!(
+                policies = settings.policies.len(),
+                "saved retention policies"
+            ),
+            Err(e) => {
+                // pruning by policies nobody configured could delete what should be kept
+                error!(error = %e, "unable to save retention policies, not applying retention");
+                return;
+            }
+        }
+        loop {
+            interval.tick().await;
+            let now = crate::now_millis() as i64;
+            let settle = settings.settle.as_millis() as i64;
+
+            match pool.conn_mut(move |conn| run(conn, now, settle)).await {
+                Ok(summary) if summary != Summary::default() => info!(
+                    hourly = summary.hourly,
+                    daily = summary.daily,
+                    pruned_samples = summary.pruned_samples,
+                    pruned_hourly = summary.pruned_hourly,
+                    pruned_daily = summary.pruned_daily,
+                    "applied retention"
+                ),
+                Ok(_) => {}
+                Err(e) => error!(error = %e, "unable to apply retention"),
+            }
+        }
+    }))
+}
+
+/// Brings the rollups up to `now - settle` and prunes what the policies no longer keep.
+/// Each step commits on its own so ingest is never blocked for the whole run.
+pub(crate) fn run(conn: &mut Connection, now: i64, settle: i64) -> rusqlite::Result<Summary> {
+    let mut summary = Summary::default();
+    let until = now - settle;
+
+    let tx = conn.transaction()?;
+    let hourly_from = progress(&tx, "hourly")?;
+    let hourly_to = until.div_euclid(HOUR_MS) * HOUR_MS;
+    if hourly_to > hourly_from {
+        summary.hourly = tx.execute(
+            "INSERT INTO
+                rollup_hourly (series_id, bucket, min, max, mean, count)
+            SELECT
+                series_id,
+                timestamp / ?3 * ?3 AS bucket,
+                MIN(value),
+                MAX(value),
+                AVG(value),
+                COUNT(value)
+            FROM sample
+            WHERE
+                timestamp >= ?1
+                AND timestamp < ?2
+            GROUP BY series_id, bucket
+            ON CONFLICT (series_id, bucket) DO UPDATE SET
+                min = excluded.min,
+                max = excluded.max,
+                mean = excluded.mean,
+                count = excluded.count",
+            params![hourly_from, hourly_to, HOUR_MS],
+        )?;
+        set_progress(&tx, "hourly", hourly_to)?;
+    }
+    tx.commit()?;
+
+    let tx = conn.transaction()?;
+    let hourly_until = progress(&tx, "hourly")?;
+    let daily_from = progress(&tx, "daily")?;
+    let daily_to = hourly_until.div_euclid(DAY_MS) * DAY_MS;
+    if daily_to > daily_from {
+        summary.daily = tx.execute(
+            "INSERT INTO
+                rollup_daily (series_id, bucket, min, max, mean, count)
+            SELECT
+                series_id,
+                bucket / ?3 * ?3 AS day,
+                MIN(min),
+                MAX(max),
+                SUM(mean * count) / SUM(count),
+                SUM(count)
+            FROM rollup_hourly
+            WHERE
+                bucket >= ?1
+                AND bucket < ?2
+            GROUP BY series_id, day
+            ON CONFLICT (series_id, bucket) DO UPDATE SET
+                min = excluded.min,
+                max = excluded.max,
+                mean = excluded.mean,
+                count = excluded.count",
+            params![daily_from, daily_to, DAY_MS],
+        )?;
+        set_progress(&tx, "daily", daily_to)?;
+    }
+    tx.commit()?;
+
+    let daily_until = progress(conn, "daily")?;
+    for policy in policies(conn)? {
+        let tx = conn.transaction()?;
+        if let Some(days) = policy.raw_days {
+            let cutoff = (now - days * DAY_MS).min(hourly_until);
+            summary.pruned_samples += tx.execute(
+                "DELETE FROM sample WHERE series_id = ?1 AND timestamp < ?2",
+                params![policy.series_id, cutoff],
+            )?;
+        }
+        if let Some(days) = policy.hourly_days {
+            let cutoff = (now - days * DAY_MS).min(daily_until);
+            summary.pruned_hourly += tx.execute(
+                "DELETE FROM rollup_hourly WHERE series_id = ?1 AND bucket < ?2",
+                params![policy.series_id, cutoff],
+            )?;
+        }
+        if let Some(days) = policy.daily_days {
+            summary.pruned_daily += tx.execute(
+                "DELETE FROM rollup_daily WHERE series_id = ?1 AND bucket < ?2",
+                params![policy.series_id, now - days * DAY_MS],
+            )?;
+        }
+        tx.commit()?;
+    }
+
+    Ok(summary)
+}
+
+/// Replaces every stored policy with `policies`.
+pub(crate) fn save_policies(
+    conn: &mut Connection,
+    policies: &[RetentionPolicy],
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM retention_policy", [])?;
+    for policy in policies {
+        tx.execute(
+            "INSERT INTO
+                retention_policy (location, measurand, raw_days, hourly_days, daily_days)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                policy.location,
+                policy.measurand,
+                policy.raw_days,
+                policy.hourly_days,
+                policy.daily_days
+            ],
+        )?;
+    }
+    tx.commit()
+}
+
+fn progress(conn: &Connection, name: &str) -> rusqlite::Result<i64> {
+    conn.query_row(
+        "SELECT until FROM rollup_progress WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    )
+}
+
+fn set_progress(conn: &Connection, name: &str, until: i64) -> rusqlite::Result<usize> {
+    conn.execute(
+        "UPDATE rollup_progress SET until = ?2 WHERE name = ?1",
+        params![name, until],
+    )
+}
+
+/// The retention policy that applies to a series.
+struct SeriesPolicy {
+    series_id: i64,
+    raw_days: Option<i64>,
+    hourly_days: Option<i64>,
+    daily_days: Option<i64>,
+}
+
+fn policies(conn: &Connection) -> rusqlite::Result<Vec<SeriesPolicy>> {
+    let mut stmt = conn.prepare(
+        "SELECT
+            series.id,
+            policy.raw_days,
+            policy.hourly_days,
+            policy.daily_days
+        FROM series
+            JOIN location ON location.id = series.location_id
+            JOIN retention_policy AS policy ON policy.id = (
+                SELECT candidate.id
+                FROM retention_policy AS candidate
+                WHERE
+                    (candidate.location IS NULL OR candidate.location = location.name)
+                    AND (candidate.measurand IS NULL OR candidate.measurand = series.measurand)
+                ORDER BY
+                    candidate.measurand IS NOT NULL DESC,
+                    candidate.location IS NOT NULL DESC
+                LIMIT 1
+            )",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(SeriesPolicy {
+            series_id: row.get(0)?,
+            raw_days: row.get(1)?,
+            hourly_days: row.get(2)?,
+            daily_days: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO series (device_id, location_id, measurand, unit) VALUES
+                (1, 1, 'temperature', 'C'),
+                (1, 1, 'humidity', '%');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn count(conn: &Connection, sql: &str) -> usize {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_rollups_aggregate_complete_buckets() {
+        let mut conn = migrated();
+        for (timestamp, value) in [(0, 1.0), (HOUR_MS / 2, 3.0), (HOUR_MS, 5.0), (DAY_MS, 7.0)] {
+            conn.execute(
+                "INSERT INTO sample (series_id, timestamp, value) VALUES (1, ?1, ?2)",
+                params![timestamp, value],
+            )
+            .unwrap();
+        }
+
+        // the hour starting at DAY_MS is still open
+        let summary = run(&mut conn, DAY_MS + HOUR_MS / 2, 0).unwrap();
+        assert_eq!(2, summary.hourly);
+        assert_eq!(1, summary.daily);
+
+        let first_hour: (f64, f64, f64, usize) = conn
+            .query_row(
+                "SELECT min, max, mean, count FROM rollup_hourly WHERE bucket = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((1.0, 3.0, 2.0, 2), first_hour);
+
+        let first_day: (f64, f64, f64, usize) = conn
+            .query_row(
+                "SELECT min, max, mean, count FROM rollup_daily WHERE bucket = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((1.0, 5.0, 3.0, 3), first_day);
+
+        // a second run finds nothing new
+        let summary = run(&mut conn, DAY_MS + HOUR_MS / 2, 0).unwrap();
+        assert_eq!(0, summary.hourly);
+    }
+
+    fn policy(location: Option<&str>, measurand: Option<&str>, raw_days: u32) -> RetentionPolicy {
+        RetentionPolicy {
+            location: location.map(str::to_string),
+            measurand: measurand.map(str::to_string),
+            raw_days: Some(raw_days),
+            hourly_days: None,
+            daily_days: None,
+        }
+    }
+
+    #[test]
+    fn test_nothing_is_pruned_without_policies() {
+        let mut conn = migrated();
+        conn.execute(
+            "INSERT INTO sample (series_id, timestamp, value) VALUES (1, 0, 1.0)",
+            [],
+        )
+        .unwrap();
+
+        let summary = run(&mut conn, 1000 * DAY_MS, 0).unwrap();
+        assert_eq!(0, summary.pruned_samples);
+        assert_eq!(1, count(&conn, "SELECT COUNT(*) FROM sample"));
+        // a scope without location and measurand is unique as well
+        assert!(
+            conn.execute(
+                "INSERT INTO retention_policy (raw_days) VALUES (1), (2)",
+                []
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_most_specific_policy_prunes() {
+        let mut conn = migrated();
+        save_policies(&mut conn, &[policy(None, None, 1)]).unwrap();
+        // saving again replaces the earlier policies
+        save_policies(
+            &mut conn,
+            &[
+                policy(Some("kitchen"), None, 10),
+                policy(None, Some("humidity"), 2),
+            ],
+        )
+        .unwrap();
+        assert_eq!(2, count(&conn, "SELECT COUNT(*) FROM retention_policy"));
+        conn.execute_batch(
+            "INSERT INTO sample (series_id, timestamp, value) VALUES
+                (1, 0, 1.0),
+                (2, 0, 1.0);",
+        )
+        .unwrap();
+
+        let summary = run(&mut conn, 5 * DAY_MS, 0).unwrap();
+        assert_eq!(1, summary.pruned_samples);
+        assert_eq!(
+            1,
+            count(&conn, "SELECT COUNT(*) FROM sample WHERE series_id = 1")
+        );
+        // the pruned sample lives on in the rollups
+        assert_eq!(
+            1,
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM rollup_hourly WHERE series_id = 2"
+            )
+        );
+    }
+}
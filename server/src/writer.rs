@@ -0,0 +1,749 @@
+//! A single task owning all ingest writes.
+//!
+//! Handlers hand their rows to the [`Writer`] instead of talking to the pool. The task
+//! groups whatever arrives within a short window into one transaction, so a packet is
+//! stored completely or not at all and SQLite syncs once per batch instead of once per
+//! reading. The queue in front of it is bounded, a flood of datagrams makes the handlers
+//! wait rather than growing memory.
+
+use std::{sync::Arc, time::Duration};
+
+use async_sqlite::{
+    Pool,
+    rusqlite::{self, Connection, params},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::metrics::Metrics;
+
+/// Acknowledged readings remembered per device. Only the packet sent last is ever
+/// repeated, this just leaves room for reordering.
+const DELIVERY_WINDOW: u32 = 64;
+
+#[derive(Error, Debug)]
+pub(crate) enum WriteError {
+    #[error("Database error: {0}")]
+    Database(#[from] async_sqlite::Error),
+    #[error("Batch failed: {0}")]
+    Batch(String),
+    #[error("Writer is shut down")]
+    Closed,
+}
+
+/// One value of a [`Write::Readings`].
+#[derive(Debug, Clone)]
+pub(crate) struct Reading {
+    pub(crate) measurand: String,
+    pub(crate) unit: String,
+    pub(crate) value: f32,
+}
+
+/// What became of a [`Write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stored {
+    /// This many rows were stored.
+    Rows(usize),
+    /// The write repeats a packet that is already stored, nothing was written.
+    Duplicate,
+}
+
+/// A packet the device sends until it is acknowledged.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Delivery {
+    pub(crate) session: u32,
+    pub(crate) sequence: u32,
+}
+
+/// Rows that have to be stored together.
+#[derive(Debug)]
+pub(crate) enum Write {
+    Readings {
+        device_id: u32,
+        location: String,
+        /// Milliseconds since the unix epoch, as are all timestamps below.
+        timestamp: i64,
+        readings: Vec<Reading>,
+        /// Set when the device waits for an ack, a repeat of it is not stored again.
+        delivery: Option<Delivery>,
+    },
+    Event {
+        device_id: u32,
+        session: u32,
+        sequence: u32,
+        location: String,
+        timestamp: i64,
+        device_timestamp: Option<i64>,
+        kind: String,
+        channel: u16,
+        payload: Option<Vec<u8>>,
+    },
+    Log {
+        device_id: u32,
+        session: u32,
+        sequence: u32,
+        timestamp: i64,
+        device_timestamp: Option<i64>,
+        level: &'static str,
+        target: String,
+        message: String,
+    },
+}
+
+enum Request {
+    Write(Write, oneshot::Sender<Result<Stored, WriteError>>),
+    Shutdown,
+}
+
+/// Limits on how much the writer collects before committing.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchSettings {
+    /// Writes waiting in front of the writer before handlers have to wait.
+    pub(crate) queue_size: usize,
+    /// Commit once this many writes are collected...
+    pub(crate) max_batch: usize,
+    /// ...or once the first write of a batch has waited this long.
+    pub(crate) max_delay: Duration,
+}
+
+/// Cheap to clone handle for submitting writes.
+#[derive(Clone)]
+pub(crate) struct Writer {
+    tx: mpsc::Sender<Request>,
+}
+
+/// Owner of the writer task, used to shut it down.
+pub(crate) struct WriterTask {
+    tx: mpsc::Sender<Request>,
+    handle: JoinHandle<()>,
+}
+
+pub(crate) fn spawn(
+    pool: Arc<Pool>,
+    settings: BatchSettings,
+    metrics: Arc<Metrics>,
+) -> (Writer, WriterTask) {
+    let (tx, rx) = mpsc::channel(settings.queue_size);
+    let handle = tokio::spawn(run(pool, rx, settings, metrics));
+    (Writer { tx: tx.clone() }, WriterTask { tx, handle })
+}
+
+impl Writer {
+    /// Queues `write` and waits until its batch is committed.
+    pub(crate) async fn write(&self, write: Write) -> Result<Stored, WriteError> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let request = Request::Write(write, done_tx);
+
+        let request = match self.tx.try_send(request) {
+            Ok(()) => None,
+            Err(mpsc::error::TrySendError::Full(request)) => {
+                warn!("ingest queue full, waiting for the writer");
+                Some(request)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return Err(WriteError::Closed),
+        };
+        if let Some(request) = request {
+            self.tx
+                .send(request)
+                .await
+                .map_err(|_| WriteError::Closed)?;
+        }
+
+        done_rx.await.map_err(|_| WriteError::Closed)?
+    }
+}
+
+impl WriterTask {
+    /// Flushes everything queued so far and stops the task.
+    pub(crate) async fn shutdown(self) {
+        if self.tx.send(Request::Shutdown).await.is_ok()
+            && let Err(e) = self.handle.await
+        {
+            error!(error = %e, "writer task failed");
+        }
+    }
+}
+
+async fn run(
+    pool: Arc<Pool>,
+    mut rx: mpsc::Receiver<Request>,
+    settings: BatchSettings,
+    metrics: Arc<Metrics>,
+) {
+    let mut batch = Vec::with_capacity(settings.max_batch);
+    let mut shutting_down = false;
+
+    while !shutting_down {
+        match rx.recv().await {
+            Some(Request::Write(write, done)) => batch.push((write, done)),
+            Some(Request::Shutdown) | None => break,
+        }
+
+        let deadline = Instant::now() + settings.max_delay;
+        while batch.len() < settings.max_batch {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(Request::Write(write, done))) => batch.push((write, done)),
+                Ok(Some(Request::Shutdown)) | Ok(None) => {
+                    shutting_down = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        flush(&pool, std::mem::take(&mut batch), &metrics).await;
+    }
+
+    // take whatever was queued before the shutdown request
+    rx.close();
+    while let Some(request) = rx.recv().await {
+        if let Request::Write(write, done) = request {
+            batch.push((write, done));
+        }
+    }
+    if !batch.is_empty() {
+        flush(&pool, batch, &metrics).await;
+    }
+    info!("writer flushed and stopped");
+}
+
+type Pending = (Write, oneshot::Sender<Result<Stored, WriteError>>);
+
+async fn flush(pool: &Pool, batch: Vec<Pending>, metrics: &Metrics) {
+    let started = Instant::now();
+    let count = batch.len();
+
+    let (writes, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    let results = pool
+        .conn_mut(move |conn| {
+            let mut tx = conn.transaction()?;
+            let results = writes
+                .iter()
+                .map(|write| {
+                    // a savepoint per write keeps one bad write from spoiling the batch
+                    let mut savepoint = tx.savepoint()?;
+                    let result = apply(&savepoint, write);
+                    match result {
+                        Ok(_) => savepoint.commit()?,
+                        Err(_) => savepoint.rollback()?,
+                    }
+                    Ok(result)
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            tx.commit()?;
+            Ok(results)
+        })
+        .await;
+
+    match results {
+        Ok(results) => {
+            metrics
+                .write_latency
+                .observe(started.elapsed().as_secs_f64());
+            for (result, sender) in results.into_iter().zip(senders) {
+                if result.is_err() {
+                    metrics.db_errors.inc();
+                }
+                let _ = sender.send(result.map_err(|e| WriteError::Database(e.into())));
+            }
+            debug!(
+                writes = count,
+                latency_us = started.elapsed().as_micros() as u64,
+                "committed batch"
+            );
+        }
+        Err(e) => {
+            error!(error = %e, writes = count, "unable to commit batch");
+            metrics.db_errors.inc_by(count as u64);
+            for sender in senders {
+                let _ = sender.send(Err(WriteError::Batch(e.to_string())));
+            }
+        }
+    }
+}
+
+/// Executes one write.
+fn apply(tx: &Connection, write: &Write) -> rusqlite::Result<Stored> {
+    match write {
+        Write::Readings {
+            device_id,
+            location,
+            timestamp,
+            readings,
+            delivery,
+        } => {
+            if let Some(Delivery { session, sequence }) = delivery {
+                let mut delivered = tx.prepare_cached(
+                    "SELECT EXISTS (
+                        SELECT 1 FROM reading_delivery
+                        WHERE device_id = ?1 AND session = ?2 AND sequence = ?3
+                    )",
+                )?;
+                if delivered.query_row(params![device_id, session, sequence], |row| row.get(0))? {
+                    return Ok(Stored::Duplicate);
+                }
+            }
+            let mut series = tx.prepare_cached(
+                "INSERT INTO
+                    series (device_id, location_id, measurand, unit)
+                SELECT
+                    ?1,
+                    location.id,
+                    ?3,
+                    ?4
+                FROM location
+                WHERE
+                    location.name = ?2
+                ON CONFLICT (device_id, location_id, measurand, unit) DO NOTHING",
+            )?;
+            let mut sample = tx.prepare_cached(
+                "INSERT INTO
+                    sample (series_id, timestamp, value)
+                SELECT
+                    series.id,
+                    ?5,
+                    ?6
+                FROM series
+                    JOIN location ON location.id = series.location_id
+                WHERE
+                    series.device_id = ?1
+                    AND location.name = ?2
+                    AND series.measurand = ?3
+                    AND series.unit = ?4",
+            )?;
+            let mut rows = 0;
+            for reading in readings {
+                series.execute(params![
+                    device_id,
+                    location,
+                    reading.measurand,
+                    reading.unit
+                ])?;
+                rows += sample.execute(params![
+                    device_id,
+                    location,
+                    reading.measurand,
+                    reading.unit,
+                    timestamp,
+                    reading.value
+                ])?;
+            }
+            // a partly stored packet isn't acknowledged, its repeat has to be tried again
+            if let Some(Delivery { session, sequence }) = delivery
+                && rows == readings.len()
+            {
+                tx.prepare_cached(
+                    "INSERT INTO reading_delivery (device_id, session, sequence) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params![device_id, session, sequence])?;
+                tx.prepare_cached(
+                    "DELETE FROM reading_delivery
+                    WHERE device_id = ?1 AND (session != ?2 OR sequence <= ?3 - ?4)",
+                )?
+                .execute(params![
+                    device_id,
+                    session,
+                    sequence,
+                    DELIVERY_WINDOW
+                ])?;
+            }
+            Ok(Stored::Rows(rows))
+        }
+        Write::Event {
+            device_id,
+            session,
+            sequence,
+            location,
+            timestamp,
+            device_timestamp,
+            kind,
+            channel,
+            payload,
+        } => {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO
+                    event (
+                        location_id, timestamp, device_timestamp, kind, channel, payload,
+                        device_id, session, sequence
+                    )
+                SELECT
+                    location.id,
+                    ?2,
+                    ?3,
+                    ?4,
+                    ?5,
+                    ?6,
+                    ?7,
+                    ?8,
+                    ?9
+                FROM location
+                WHERE
+                    location.name = ?1
+                LIMIT 1
+                ON CONFLICT (device_id, session, sequence) DO NOTHING",
+            )?;
+            let rows = stmt.execute(params![
+                location,
+                timestamp,
+                device_timestamp,
+                kind,
+                channel,
+                payload,
+                device_id,
+                session,
+                sequence
+            ])?;
+            // nothing stored is either an unknown location or an ack that got lost
+            let mut stored = tx.prepare_cached(
+                "SELECT EXISTS (
+                    SELECT 1 FROM event WHERE device_id = ?1 AND session = ?2 AND sequence = ?3
+                )",
+            )?;
+            if rows == 0
+                && stored.query_row(params![device_id, session, sequence], |row| row.get(0))?
+            {
+                return Ok(Stored::Duplicate);
+            }
+            // a command sent before the device restarted may have run already, the device
+            // forgot which ids it executed, so it must not get them again
+            if rows == 1 && kind == "boot" {
+                let mut unconfirmed = tx.prepare_cached(
+                    "UPDATE command
+                    SET status = 'unconfirmed'
+                    WHERE device_id = ?1 AND status = 'sent'",
+                )?;
+                unconfirmed.execute([device_id])?;
+            }
+            Ok(Stored::Rows(rows))
+        }
+        Write::Log {
+            device_id,
+            session,
+            sequence,
+            timestamp,
+            device_timestamp,
+            level,
+            target,
+            message,
+        } => {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO
+                    device_log (
+                        device_id, timestamp, device_timestamp, level, target, message,
+                        session, sequence
+                    )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (device_id, session, sequence) DO NOTHING",
+            )?;
+            let rows = stmt.execute(params![
+                device_id,
+                timestamp,
+                device_timestamp,
+                level,
+                target,
+                message,
+                session,
+                sequence
+            ])?;
+            // without a location to look up, nothing stored means an ack got lost
+            Ok(match rows {
+                0 => Stored::Duplicate,
+                rows => Stored::Rows(rows),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_sqlite::JournalMode;
+
+    use crate::db::{self, DbSettings};
+
+    /// Fails the test instead of hanging when a write is never committed.
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    async fn open(name: &str) -> (Arc<Pool>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "server-writer-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = DbSettings {
+            path: dir.join("db.sqlite3").to_str().unwrap().to_string(),
+            legacy_path: None,
+            journal_mode: JournalMode::Wal,
+            synchronous: "NORMAL",
+            busy_timeout: Duration::from_secs(5),
+            checkpoint_interval: Duration::ZERO,
+            pool_size: 1,
+            read_connections: 1,
+        };
+        let pool = db::open(&settings).await.unwrap();
+        // lets a test make a single write fail
+        pool.conn(|conn| {
+            conn.execute_batch(
+                "CREATE TRIGGER reject BEFORE INSERT ON device_log WHEN NEW.message = 'bad'
+                BEGIN
+                    SELECT RAISE(ABORT, 'rejected');
+                END;",
+            )
+        })
+        .await
+        .unwrap();
+        (Arc::new(pool), dir)
+    }
+
+    fn spawn_writer(
+        pool: &Arc<Pool>,
+        max_batch: usize,
+        max_delay: Duration,
+    ) -> (Writer, WriterTask, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new(false));
+        let settings = BatchSettings {
+            queue_size: 16,
+            max_batch,
+            max_delay,
+        };
+        let (writer, task) = spawn(pool.clone(), settings, metrics.clone());
+        (writer, task, metrics)
+    }
+
+    fn log(sequence: u32, message: &str) -> Write {
+        Write::Log {
+            device_id: 1,
+            session: 1,
+            sequence,
+            timestamp: 1000,
+            device_timestamp: None,
+            level: "warn",
+            target: "test".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn event(sequence: u32, kind: &str) -> Write {
+        Write::Event {
+            device_id: 1,
+            session: 1,
+            sequence,
+            location: "kitchen".to_string(),
+            timestamp: 1000,
+            device_timestamp: None,
+            kind: kind.to_string(),
+            channel: 0,
+            payload: None,
+        }
+    }
+
+    /// How many batches were committed.
+    fn batches(metrics: &Metrics) -> u64 {
+        metrics
+            .encode()
+            .lines()
+            .find_map(|line| line.strip_prefix("sensors_db_write_seconds_count "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    async fn messages(pool: &Pool) -> Vec<String> {
+        pool.conn(|conn| {
+            let mut stmt = conn.prepare("SELECT message FROM device_log ORDER BY id")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn close(pool: Arc<Pool>, dir: std::path::PathBuf) {
+        pool.close().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_batch_commits_without_waiting() {
+        let (pool, dir) = open("count").await;
+        // the window is far longer than the test may take, only the count can commit
+        let (writer, task, metrics) = spawn_writer(&pool, 2, Duration::from_secs(3600));
+
+        let (first, second) = tokio::time::timeout(
+            PATIENCE,
+            futures_util::future::join(writer.write(log(1, "one")), writer.write(log(2, "two"))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Stored::Rows(1), first.unwrap());
+        assert_eq!(Stored::Rows(1), second.unwrap());
+        assert_eq!(1, batches(&metrics));
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_window_commits_partial_batch() {
+        let (pool, dir) = open("window").await;
+        let window = Duration::from_millis(100);
+        let (writer, task, metrics) = spawn_writer(&pool, 100, window);
+
+        let started = Instant::now();
+        let (first, second) = tokio::time::timeout(
+            PATIENCE,
+            futures_util::future::join(writer.write(log(1, "one")), writer.write(log(2, "two"))),
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() >= window);
+        assert_eq!(Stored::Rows(1), first.unwrap());
+        assert_eq!(Stored::Rows(1), second.unwrap());
+        assert_eq!(1, batches(&metrics));
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_spares_the_rest_of_its_batch() {
+        let (pool, dir) = open("savepoint").await;
+        let (writer, task, metrics) = spawn_writer(&pool, 3, Duration::from_secs(3600));
+
+        let (first, bad, last) = tokio::time::timeout(
+            PATIENCE,
+            futures_util::future::join3(
+                writer.write(log(1, "first")),
+                writer.write(log(2, "bad")),
+                writer.write(log(3, "last")),
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Stored::Rows(1), first.unwrap());
+        assert!(matches!(bad, Err(WriteError::Database(_))));
+        assert_eq!(Stored::Rows(1), last.unwrap());
+        assert_eq!(1, batches(&metrics));
+        assert_eq!(vec!["first", "last"], messages(&pool).await);
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_waiting_writes() {
+        let (pool, dir) = open("shutdown").await;
+        let (writer, task, _) = spawn_writer(&pool, 100, Duration::from_secs(3600));
+
+        let pending = tokio::spawn({
+            let writer = writer.clone();
+            async move { writer.write(log(1, "waiting")).await }
+        });
+        // let the writer pick it up, the batch would then wait out the whole window
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        tokio::time::timeout(PATIENCE, task.shutdown())
+            .await
+            .unwrap();
+        assert_eq!(Stored::Rows(1), pending.await.unwrap().unwrap());
+        assert_eq!(vec!["waiting"], messages(&pool).await);
+        assert!(matches!(
+            writer.write(log(2, "late")).await,
+            Err(WriteError::Closed)
+        ));
+
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_retransmitted_log_is_stored_once() {
+        let (pool, dir) = open("log-delivery").await;
+        let (writer, task, _) = spawn_writer(&pool, 1, Duration::ZERO);
+
+        assert_eq!(Stored::Rows(1), writer.write(log(7, "once")).await.unwrap());
+        assert_eq!(
+            Stored::Duplicate,
+            writer.write(log(7, "once")).await.unwrap()
+        );
+        // sequences start over with every registration
+        let mut next_session = log(7, "again");
+        if let Write::Log { session, .. } = &mut next_session {
+            *session = 2;
+        }
+        assert_eq!(Stored::Rows(1), writer.write(next_session).await.unwrap());
+        assert_eq!(vec!["once", "again"], messages(&pool).await);
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_retransmitted_event_is_stored_once() {
+        let (pool, dir) = open("event-delivery").await;
+        let (writer, task, _) = spawn_writer(&pool, 1, Duration::ZERO);
+
+        assert_eq!(
+            Stored::Rows(1),
+            writer.write(event(4, "button")).await.unwrap()
+        );
+        assert_eq!(
+            Stored::Duplicate,
+            writer.write(event(4, "button")).await.unwrap()
+        );
+        assert_eq!(
+            Stored::Rows(1),
+            writer.write(event(5, "button")).await.unwrap()
+        );
+        let events: Vec<(u32, u32)> = pool
+            .conn(|conn| {
+                let mut stmt = conn.prepare("SELECT session, sequence FROM event ORDER BY id")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect()
+            })
+            .await
+            .unwrap();
+        assert_eq!(vec![(1, 4), (1, 5)], events);
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_boot_event_stops_resending_commands() {
+        let (pool, dir) = open("boot").await;
+        let (writer, task, _) = spawn_writer(&pool, 1, Duration::ZERO);
+        pool.conn(|conn| {
+            conn.execute_batch(
+                "INSERT INTO command (device_id, kind, status) VALUES
+                    (1, 'reboot', 'sent'),
+                    (1, 'identify', 'pending'),
+                    (2, 'reboot', 'sent');",
+            )
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            Stored::Rows(1),
+            writer.write(event(1, "boot")).await.unwrap()
+        );
+        let statuses: Vec<String> = pool
+            .conn(|conn| {
+                let mut stmt = conn.prepare("SELECT status FROM command ORDER BY id")?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect()
+            })
+            .await
+            .unwrap();
+        assert_eq!(vec!["unconfirmed", "pending", "sent"], statuses);
+
+        task.shutdown().await;
+        close(pool, dir).await;
+    }
+}